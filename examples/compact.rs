@@ -17,7 +17,11 @@ fn run(config: Config) -> Result<()> {
             config.db_path
         );
     }
-    let store = DBStore::open(&config.db_path, /*low_memory=*/ true);
+    let store = DBStore::open(
+        &config.db_path,
+        /*low_memory=*/ true,
+        config.db_cache_size,
+    )?;
     store.compact();
     Ok(())
 }
@@ -26,7 +26,14 @@ fn run() -> Result<()> {
         &metrics,
     )?;
     let fake_store = FakeStore {};
-    let index = Index::load(&fake_store, &daemon, &metrics, config.index_batch_size)?;
+    let index = Index::load(
+        &fake_store,
+        &daemon,
+        &metrics,
+        config.index_batch_size,
+        config.index_utxos,
+        config.store_txs,
+    )?;
     index.update(&fake_store, &signal)?;
     Ok(())
 }
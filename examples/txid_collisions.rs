@@ -3,14 +3,17 @@ extern crate electrs;
 extern crate hex;
 extern crate log;
 
-use electrs::{config::Config, store::DBStore};
+use electrs::{
+    config::Config,
+    store::{DBStore, Family, ReadStore},
+};
 
 fn max_collision(store: DBStore, prefix: &[u8]) {
     let prefix_len = prefix.len();
     let mut prev: Option<Vec<u8>> = None;
     let mut collision_max = 0;
 
-    for row in store.iter_scan(prefix) {
+    for row in store.scan(Family::of(prefix), prefix) {
         assert!(row.key.starts_with(prefix));
         if let Some(prev) = prev {
             let collision_len = prev
@@ -40,7 +43,12 @@ fn run(config: Config) {
     if !config.db_path.exists() {
         panic!("DB {:?} must exist when running this tool!", config.db_path);
     }
-    let store = DBStore::open(&config.db_path, /*low_memory=*/ false);
+    let store = DBStore::open(
+        &config.db_path,
+        /*low_memory=*/ false,
+        config.db_cache_size,
+    )
+    .expect("failed to open DB");
     max_collision(store, b"T");
 }
 
@@ -1,7 +1,38 @@
 use bitcoin::util::hash::Sha256dHash;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
 
-use crate::{config::Config, daemon, errors::*, index, signal::Waiter, store};
+use crate::{
+    config::Config,
+    daemon,
+    errors::*,
+    index,
+    index::Changes,
+    signal::Waiter,
+    store,
+    util::{HeaderEntry, HeaderList},
+};
+
+/// A consistent view of the index: a DB snapshot, together with the headers of its blocks.
+pub struct Snapshot {
+    store: store::SnapshotStore,
+    headers: Arc<HeaderList>,
+}
+
+impl Snapshot {
+    pub fn store(&self) -> &store::ReadStore {
+        &self.store
+    }
+
+    pub fn best_header(&self) -> Option<HeaderEntry> {
+        self.headers
+            .header_by_blockhash(self.headers.tip())
+            .cloned()
+    }
+
+    pub fn get_header(&self, height: usize) -> Option<HeaderEntry> {
+        self.headers.header_by_height(height).cloned()
+    }
+}
 
 pub struct App {
     store: store::DBStore,
@@ -9,6 +40,7 @@ pub struct App {
     daemon: daemon::Daemon,
     banner: String,
     tip: Mutex<Sha256dHash>,
+    snapshot: RwLock<Arc<Snapshot>>, // published after each index update
 }
 
 impl App {
@@ -18,21 +50,23 @@ impl App {
         daemon: daemon::Daemon,
         config: &Config,
     ) -> Result<Arc<App>> {
+        let snapshot = Snapshot {
+            store: store.snapshot(),
+            headers: index.headers(),
+        };
         Ok(Arc::new(App {
             store,
             index,
             daemon: daemon.reconnect()?,
             banner: config.server_banner.clone(),
             tip: Mutex::new(Sha256dHash::default()),
+            snapshot: RwLock::new(Arc::new(snapshot)),
         }))
     }
 
-    fn write_store(&self) -> &store::WriteStore {
-        &self.store
-    }
-    // TODO: use index for queries.
-    pub fn read_store(&self) -> &store::ReadStore {
-        &self.store
+    /// Returns the latest published snapshot, to be used for all the lookups of a query.
+    pub fn snapshot(&self) -> Arc<Snapshot> {
+        Arc::clone(&self.snapshot.read().unwrap())
     }
     pub fn index(&self) -> &index::Index {
         &self.index
@@ -41,13 +75,22 @@ impl App {
         &self.daemon
     }
 
-    pub fn update(&self, signal: &Waiter) -> Result<bool> {
+    /// Indexes new blocks (if any), returning the changes made by their transactions.
+    pub fn update(&self, signal: &Waiter) -> Result<Changes> {
         let mut tip = self.tip.lock().expect("failed to lock tip");
         let new_block = *tip != self.daemon().getbestblockhash()?;
-        if new_block {
-            *tip = self.index().update(self.write_store(), &signal)?;
+        if !new_block {
+            return Ok(Changes::default());
         }
-        Ok(new_block)
+        let (new_tip, changes) = self.index().update(&self.store, &signal)?;
+        // the new rows are already written, so the snapshot covers all the new headers
+        let snapshot = Snapshot {
+            store: self.store.snapshot(),
+            headers: self.index().headers(),
+        };
+        *self.snapshot.write().unwrap() = Arc::new(snapshot);
+        *tip = new_tip;
+        Ok(changes)
     }
 
     pub fn get_banner(&self) -> Result<String> {
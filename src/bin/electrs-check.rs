@@ -0,0 +1,90 @@
+extern crate electrs;
+
+#[macro_use]
+extern crate clap;
+#[macro_use]
+extern crate error_chain;
+#[macro_use]
+extern crate log;
+
+use clap::{Arg, ArgMatches};
+use error_chain::ChainedError;
+use std::process;
+
+use electrs::{
+    check::Checker, config::Config, daemon::Daemon, errors::*, metrics::Metrics, signal::Waiter,
+    store::DBStore,
+};
+
+fn check_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("from_height")
+            .long("from-height")
+            .help("First block height to check (or repair)")
+            .default_value("0"),
+        Arg::with_name("to_height")
+            .long("to-height")
+            .help("Last block height to check (or repair), inclusive (default: the last indexed block)")
+            .takes_value(true),
+        Arg::with_name("sample_interval")
+            .long("sample-interval")
+            .help("Check every N-th output and input row of the checked blocks")
+            .default_value("100"),
+        Arg::with_name("repair")
+            .long("repair")
+            .help("Reindex the blocks at the specified heights (using JSONRPC), before checking them"),
+    ]
+}
+
+fn run_check(config: &Config, m: &ArgMatches) -> Result<()> {
+    let signal = Waiter::new();
+    let metrics = Metrics::new(config.monitoring_addr);
+    let daemon = Daemon::new(
+        &config.daemon_dir,
+        config.daemon_rpc_addr,
+        config.cookie_getter(),
+        config.network_type,
+        signal,
+        &metrics,
+    )?;
+    let store = DBStore::open(
+        &config.db_path,
+        /*low_memory=*/ true,
+        config.db_cache_size,
+    )?;
+    let mut checker = Checker::new(&store, &daemon, config.index_batch_size)?;
+    let from_height = value_t_or_exit!(m, "from_height", usize);
+    let sample_interval = value_t_or_exit!(m, "sample_interval", usize);
+    if sample_interval == 0 {
+        bail!("--sample-interval must be positive");
+    }
+    if m.is_present("repair") {
+        if !m.is_present("to_height") {
+            bail!("--to-height must be specified for repair");
+        }
+        checker.repair(from_height, value_t_or_exit!(m, "to_height", usize))?;
+    }
+    let last_height = checker.check_headers();
+    let to_height = if m.is_present("to_height") {
+        value_t_or_exit!(m, "to_height", usize)
+    } else {
+        match last_height {
+            Some(height) => height,
+            None => bail!("no indexed blocks of the best chain to check"),
+        }
+    };
+    checker.check_blocks(from_height, to_height, sample_interval)?;
+    if checker.problems() > 0 {
+        bail!("found {} problems", checker.problems());
+    }
+    info!("no problems found");
+    Ok(())
+}
+
+fn main() {
+    let (config, m) = Config::from_args_with(check_args());
+    if let Err(e) = run_check(&config, &m) {
+        error!("check failed: {}", e.display_chain());
+        process::exit(1);
+    }
+}
@@ -20,12 +20,18 @@ use electrs::{
     rpc::RPC,
     signal::Waiter,
     store::{full_compaction, is_fully_compacted, DBStore},
+    tls::TlsAcceptor,
 };
 
 fn run_server(config: &Config) -> Result<()> {
     let signal = Waiter::new();
     let metrics = Metrics::new(config.monitoring_addr);
     metrics.start();
+    // Load the TLS certificate before indexing, so a bad one is reported right away.
+    let mut tls = match (&config.ssl_certfile, &config.ssl_keyfile) {
+        (Some(certfile), Some(keyfile)) => Some(TlsAcceptor::new(certfile, keyfile)?),
+        _ => None,
+    };
 
     let daemon = Daemon::new(
         &config.daemon_dir,
@@ -36,8 +42,19 @@ fn run_server(config: &Config) -> Result<()> {
         &metrics,
     )?;
     // Perform initial indexing from local blk*.dat block files.
-    let store = DBStore::open(&config.db_path, /*low_memory=*/ config.jsonrpc_import);
-    let index = Index::load(&store, &daemon, &metrics, config.index_batch_size)?;
+    let store = DBStore::open(
+        &config.db_path,
+        /*low_memory=*/ config.jsonrpc_import,
+        config.db_cache_size,
+    )?;
+    let index = Index::load(
+        &store,
+        &daemon,
+        &metrics,
+        config.index_batch_size,
+        config.index_utxos,
+        config.store_txs,
+    )?;
     let store = if is_fully_compacted(&store) {
         store // initial import and full compaction are over
     } else {
@@ -46,13 +63,21 @@ fn run_server(config: &Config) -> Result<()> {
             full_compaction(store)
         } else {
             // faster, but uses more memory
-            let store = bulk::index_blk_files(&daemon, config.bulk_index_threads, &metrics, store)?;
+            let store = bulk::index_blk_files(
+                &daemon,
+                config.bulk_index_threads,
+                &metrics,
+                store,
+                config.index_utxos,
+                config.store_txs,
+            )?;
             let store = full_compaction(store);
             index.reload(&store); // make sure the block header index is up-to-date
             store
         }
     }
     .enable_compaction(); // enable auto compactions before starting incremental index updates.
+    store.start_stats_exporter(&metrics);
 
     let app = App::new(store, index, daemon, &config)?;
     let tx_cache = TransactionCache::new(config.tx_cache_size);
@@ -60,11 +85,15 @@ fn run_server(config: &Config) -> Result<()> {
 
     let mut server = None; // Electrum RPC server
     loop {
-        app.update(&signal)?;
+        let mut changes = app.update(&signal)?;
         query.update_mempool()?;
-        server
-            .get_or_insert_with(|| RPC::start(config.electrum_rpc_addr, query.clone(), &metrics))
-            .notify(); // update subscribed clients
+        changes.extend(query.take_mempool_changes());
+        if server.is_none() {
+            server = Some(RPC::start(config, tls.take(), query.clone(), &metrics)?);
+        }
+        if let Some(server) = &server {
+            server.notify(changes); // update affected subscriptions
+        }
         if let Err(err) = signal.wait(Duration::from_secs(5)) {
             info!("stopping server: {}", err);
             break;
@@ -1,10 +1,11 @@
 use bitcoin::blockdata::block::Block;
-use bitcoin::consensus::encode::{deserialize, Decodable};
+use bitcoin::consensus::encode::deserialize;
 use bitcoin::util::hash::{BitcoinHash, Sha256dHash};
 use libc;
+use memmap::Mmap;
+use std::borrow::Cow;
 use std::collections::HashSet;
 use std::fs;
-use std::io::{Cursor, Seek, SeekFrom};
 use std::path::{Path, PathBuf};
 use std::sync::{
     mpsc::{Receiver, SyncSender},
@@ -14,15 +15,21 @@ use std::thread;
 
 use crate::daemon::Daemon;
 use crate::errors::*;
-use crate::index::{index_block, last_indexed_block, read_indexed_blockhashes};
+use crate::index::{
+    block_undo_row, delete_spent_utxos, index_block, last_indexed_block, read_indexed_blockhashes,
+    spent_output_markers, MAX_REORG_DEPTH,
+};
 use crate::metrics::{CounterVec, Histogram, HistogramOpts, HistogramVec, MetricOpts, Metrics};
 use crate::store::{DBStore, Row, WriteStore};
-use crate::util::{spawn_thread, HeaderList, SyncChannel};
+use crate::util::{spawn_thread, Channel, HeaderList, SyncChannel};
 
 struct Parser {
     magic: u32,
     current_headers: HeaderList,
     indexed_blockhashes: Mutex<HashSet<Sha256dHash>>,
+    index_utxos: bool,
+    store_txs: bool,
+    xor_key: Option<Vec<u8>>, // of the blk*.dat files (if obfuscated)
     // metrics
     duration: HistogramVec,
     block_count: CounterVec,
@@ -34,11 +41,17 @@ impl Parser {
         daemon: &Daemon,
         metrics: &Metrics,
         indexed_blockhashes: HashSet<Sha256dHash>,
+        index_utxos: bool,
+        store_txs: bool,
+        xor_key: Option<Vec<u8>>,
     ) -> Result<Arc<Parser>> {
         Ok(Arc::new(Parser {
             magic: daemon.magic(),
             current_headers: load_headers(daemon)?,
             indexed_blockhashes: Mutex::new(indexed_blockhashes),
+            index_utxos,
+            store_txs,
+            xor_key,
             duration: metrics.histogram_vec(
                 HistogramOpts::new("parse_duration", "blk*.dat parsing duration (in seconds)"),
                 &["step"],
@@ -55,90 +68,192 @@ impl Parser {
         }))
     }
 
-    fn last_indexed_row(&self) -> Row {
+    fn last_indexed_row(&self) -> Option<Row> {
         // TODO: use JSONRPC for missing blocks, and don't use 'L' row at all.
         let indexed_blockhashes = self.indexed_blockhashes.lock().unwrap();
         let last_header = self
             .current_headers
             .iter()
             .take_while(|h| indexed_blockhashes.contains(h.hash()))
-            .last()
-            .expect("no indexed header found");
+            .last();
         debug!("last indexed block: {:?}", last_header);
-        last_indexed_block(last_header.hash())
+        Some(last_indexed_block(last_header?.hash()))
     }
 
-    fn read_blkfile(&self, path: &Path) -> Result<Vec<u8>> {
+    // The file is mapped (instead of being read), so its pages can be dropped from memory
+    // after its blocks are parsed.
+    fn map_blkfile(&self, path: &Path) -> Result<Option<Mmap>> {
         let timer = self.duration.with_label_values(&["read"]).start_timer();
-        let blob = fs::read(&path).chain_err(|| format!("failed to read {:?}", path))?;
+        let file = fs::File::open(path).chain_err(|| format!("failed to open {:?}", path))?;
+        let len = file
+            .metadata()
+            .chain_err(|| format!("failed to stat {:?}", path))?
+            .len();
+        if len == 0 {
+            return Ok(None); // empty files can't be mapped
+        }
+        // blk*.dat files are only appended to by bitcoind, so the mapped part is not modified
+        let mmap = unsafe { Mmap::map(&file) }.chain_err(|| format!("failed to map {:?}", path))?;
         timer.observe_duration();
-        self.bytes_read.observe(blob.len() as f64);
-        return Ok(blob);
+        self.bytes_read.observe(len as f64);
+        Ok(Some(mmap))
     }
 
-    fn index_blkfile(&self, blob: Vec<u8>) -> Result<Vec<Row>> {
-        let timer = self.duration.with_label_values(&["parse"]).start_timer();
-        let blocks = parse_blocks(blob, self.magic)?;
-        timer.observe_duration();
+    // Indexes the blocks one at a time, sending their rows to the writer in bounded batches.
+    fn index_blkfile(&self, path: &Path, writer: &SyncSender<(Vec<Row>, PathBuf)>) -> Result<()> {
+        let mmap = match self.map_blkfile(path)? {
+            Some(mmap) => mmap,
+            None => return Ok(()),
+        };
+        let mut blocks = BlockIter::new(&mmap, self.magic, self.xor_key.as_ref());
+        let mut rows = vec![];
+        let mut batch_size = 0;
+        loop {
+            let timer = self.duration.with_label_values(&["parse"]).start_timer();
+            let block = match blocks.next() {
+                Some(block) => block?,
+                None => break,
+            };
+            timer.observe_duration();
+
+            let timer = self.duration.with_label_values(&["index"]).start_timer();
+            for row in self.index_block(&block) {
+                batch_size += row.key.len() + row.value.len();
+                rows.push(row);
+            }
+            timer.observe_duration();
+            if batch_size >= MAX_BATCH_SIZE {
+                self.send_rows(rows, path, writer);
+                rows = vec![];
+                batch_size = 0;
+            }
+        }
+        self.send_rows(rows, path, writer);
+        Ok(())
+    }
 
-        let mut rows = Vec::<Row>::new();
-        let timer = self.duration.with_label_values(&["index"]).start_timer();
-        for block in blocks {
-            let blockhash = block.bitcoin_hash();
-            if let Some(header) = self.current_headers.header_by_blockhash(&blockhash) {
-                if self
-                    .indexed_blockhashes
-                    .lock()
-                    .expect("indexed_blockhashes")
-                    .insert(blockhash.clone())
-                {
-                    rows.extend(index_block(&block, header.height()));
-                    self.block_count.with_label_values(&["indexed"]).inc();
-                } else {
-                    self.block_count.with_label_values(&["duplicate"]).inc();
+    fn index_block(&self, block: &Block) -> Vec<Row> {
+        let blockhash = block.bitcoin_hash();
+        if let Some(header) = self.current_headers.header_by_blockhash(&blockhash) {
+            let height = header.height();
+            let recent = height + MAX_REORG_DEPTH >= self.current_headers.len();
+            if recent && self.index_utxos {
+                // indexed later (using JSONRPC), so their undo data has the UTXO rows they spend
+                self.block_count.with_label_values(&["skipped"]).inc();
+                return vec![];
+            }
+            if self
+                .indexed_blockhashes
+                .lock()
+                .expect("indexed_blockhashes")
+                .insert(blockhash.clone())
+            {
+                let mut block_rows = index_block(block, height, self.index_utxos, self.store_txs);
+                if self.index_utxos {
+                    // the spent outputs may be indexed later (e.g. by another thread)
+                    block_rows.extend(spent_output_markers(block));
+                } else if recent {
+                    block_rows.push(block_undo_row(&blockhash, height, &block_rows, &[]));
                 }
+                self.block_count.with_label_values(&["indexed"]).inc();
+                return block_rows;
             } else {
-                // will be indexed later (after bulk load is over) if not an orphan block
-                self.block_count.with_label_values(&["skipped"]).inc();
+                self.block_count.with_label_values(&["duplicate"]).inc();
             }
+        } else {
+            // will be indexed later (after bulk load is over) if not an orphan block
+            self.block_count.with_label_values(&["skipped"]).inc();
         }
-        timer.observe_duration();
+        vec![]
+    }
 
+    fn send_rows(&self, mut rows: Vec<Row>, path: &Path, writer: &SyncSender<(Vec<Row>, PathBuf)>) {
+        if rows.is_empty() {
+            return;
+        }
         let timer = self.duration.with_label_values(&["sort"]).start_timer();
         rows.sort_unstable_by(|a, b| a.key.cmp(&b.key));
         timer.observe_duration();
-        Ok(rows)
+        writer
+            .send((rows, path.to_path_buf()))
+            .expect("failed to send indexed rows")
     }
 }
 
-fn parse_blocks(blob: Vec<u8>, magic: u32) -> Result<Vec<Block>> {
-    let mut cursor = Cursor::new(&blob);
-    let mut blocks = vec![];
-    let max_pos = blob.len() as u64;
-    while cursor.position() < max_pos {
-        match u32::consensus_decode(&mut cursor) {
-            Ok(value) => {
-                if magic != value {
-                    cursor
-                        .seek(SeekFrom::Current(-3))
-                        .expect("failed to seek back");
-                    continue;
-                }
+// The total size (in bytes) of the rows sent to the writer at once, bounding the memory used
+// by each indexing thread (since the writer has no queue).
+const MAX_BATCH_SIZE: usize = 32 << 20;
+
+// Reverses (or applies) the obfuscation of the file's bytes, starting at `offset`.
+fn xor(bytes: &mut [u8], key: &[u8], offset: usize) {
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte ^= key[(offset + i) % key.len()];
+    }
+}
+
+/// Parses the (possibly obfuscated) blocks of a blk*.dat file, one at a time.
+struct BlockIter<'a> {
+    data: &'a [u8],
+    pos: usize,
+    magic: u32,
+    xor_key: Option<&'a Vec<u8>>,
+}
+
+impl<'a> BlockIter<'a> {
+    fn new(data: &'a [u8], magic: u32, xor_key: Option<&'a Vec<u8>>) -> Self {
+        BlockIter {
+            data,
+            pos: 0,
+            magic,
+            xor_key,
+        }
+    }
+
+    // Returns the (deobfuscated) bytes at the specified range, copying them only if needed.
+    fn read(&self, start: usize, len: usize) -> Option<Cow<'a, [u8]>> {
+        let bytes = self.data.get(start..start.checked_add(len)?)?;
+        Some(match self.xor_key {
+            Some(key) => {
+                let mut bytes = bytes.to_vec();
+                xor(&mut bytes, key, start);
+                Cow::Owned(bytes)
             }
-            Err(_) => break, // EOF
-        };
-        let block_size = u32::consensus_decode(&mut cursor).chain_err(|| "no block size")?;
-        let start = cursor.position() as usize;
-        cursor
-            .seek(SeekFrom::Current(block_size as i64))
-            .chain_err(|| format!("seek {} failed", block_size))?;
-        let end = cursor.position() as usize;
-
-        let block: Block = deserialize(&blob[start..end])
-            .chain_err(|| format!("failed to parse block at {}..{}", start, end))?;
-        blocks.push(block);
-    }
-    Ok(blocks)
+            None => Cow::Borrowed(bytes),
+        })
+    }
+
+    fn read_u32(&self, start: usize) -> Option<u32> {
+        Some(deserialize(&self.read(start, 4)?).unwrap())
+    }
+}
+
+impl<'a> Iterator for BlockIter<'a> {
+    type Item = Result<Block>;
+
+    fn next(&mut self) -> Option<Result<Block>> {
+        loop {
+            if self.read_u32(self.pos)? != self.magic {
+                self.pos += 1; // skip the padding (e.g. zeros at the end of the file)
+                continue;
+            }
+            let size = self.read_u32(self.pos + 4)? as usize;
+            let start = self.pos + 8;
+            let bytes = match self.read(start, size) {
+                Some(bytes) => bytes,
+                None => {
+                    // may be written by bitcoind right now, so it will be indexed using JSONRPC
+                    warn!("truncated block at {}..{}", start, start + size);
+                    self.pos = self.data.len();
+                    return None;
+                }
+            };
+            self.pos = start + size;
+            return Some(
+                deserialize(&bytes)
+                    .chain_err(|| format!("failed to parse block at {}..{}", start, start + size)),
+            );
+        }
+    }
 }
 
 fn load_headers(daemon: &Daemon) -> Result<HeaderList> {
@@ -167,37 +282,20 @@ fn set_open_files_limit(limit: libc::rlim_t) {
 }
 
 type JoinHandle = thread::JoinHandle<Result<()>>;
-type BlobReceiver = Arc<Mutex<Receiver<(Vec<u8>, PathBuf)>>>;
-
-fn start_reader(blk_files: Vec<PathBuf>, parser: Arc<Parser>) -> (BlobReceiver, JoinHandle) {
-    let chan = SyncChannel::new(0);
-    let blobs = chan.sender();
-    let handle = spawn_thread("bulk_read", move || -> Result<()> {
-        for path in blk_files {
-            blobs
-                .send((parser.read_blkfile(&path)?, path))
-                .expect("failed to send blk*.dat contents");
-        }
-        Ok(())
-    });
-    (Arc::new(Mutex::new(chan.into_receiver())), handle)
-}
+type PathReceiver = Arc<Mutex<Receiver<PathBuf>>>;
 
 fn start_indexer(
-    blobs: BlobReceiver,
+    paths: PathReceiver,
     parser: Arc<Parser>,
     writer: SyncSender<(Vec<Row>, PathBuf)>,
 ) -> JoinHandle {
     spawn_thread("bulk_index", move || -> Result<()> {
         loop {
-            let msg = blobs.lock().unwrap().recv();
-            if let Ok((blob, path)) = msg {
-                let rows = parser
-                    .index_blkfile(blob)
+            let msg = paths.lock().unwrap().recv();
+            if let Ok(path) = msg {
+                parser
+                    .index_blkfile(&path, &writer)
                     .chain_err(|| format!("failed to index {:?}", path))?;
-                writer
-                    .send((rows, path))
-                    .expect("failed to send indexed rows")
             } else {
                 debug!("no more blocks to index");
                 break;
@@ -212,36 +310,201 @@ pub fn index_blk_files(
     index_threads: usize,
     metrics: &Metrics,
     store: DBStore,
+    index_utxos: bool,
+    store_txs: bool,
 ) -> Result<DBStore> {
     set_open_files_limit(2048); // twice the default `ulimit -n` value
     let blk_files = daemon.list_blk_files()?;
-    info!("indexing {} blk*.dat files", blk_files.len());
+    info!("indexing {} blk*.dat files", blk_files.paths.len());
     let indexed_blockhashes = read_indexed_blockhashes(&store);
     debug!("found {} indexed blocks", indexed_blockhashes.len());
-    let parser = Parser::new(daemon, metrics, indexed_blockhashes)?;
-    let (blobs, reader) = start_reader(blk_files, parser.clone());
+    let parser = Parser::new(
+        daemon,
+        metrics,
+        indexed_blockhashes,
+        index_utxos,
+        store_txs,
+        blk_files.xor_key,
+    )?;
+    let paths_chan = Channel::new();
+    for path in blk_files.paths {
+        paths_chan.sender().send(path).unwrap();
+    }
+    let paths = Arc::new(Mutex::new(paths_chan.into_receiver()));
     let rows_chan = SyncChannel::new(0);
     let indexers: Vec<JoinHandle> = (0..index_threads)
-        .map(|_| start_indexer(blobs.clone(), parser.clone(), rows_chan.sender()))
+        .map(|_| start_indexer(paths.clone(), parser.clone(), rows_chan.sender()))
         .collect();
     Ok(spawn_thread("bulk_writer", move || -> DBStore {
         for (rows, path) in rows_chan.into_receiver() {
             trace!("indexed {:?}: {} rows", path, rows.len());
             store.write(rows);
         }
-        reader
-            .join()
-            .expect("reader panicked")
-            .expect("reader failed");
-
         indexers.into_iter().for_each(|i| {
             i.join()
                 .expect("indexer panicked")
                 .expect("indexing failed")
         });
-        store.write(vec![parser.last_indexed_row()]);
+        if index_utxos {
+            delete_spent_utxos(&store);
+        }
+        store.write(parser.last_indexed_row().into_iter().collect());
         store
     })
     .join()
     .expect("writer panicked"))
 }
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::blockdata::script::Script;
+    use bitcoin::blockdata::transaction::{OutPoint, Transaction, TxIn, TxOut};
+    use bitcoin::consensus::encode::serialize;
+    use bitcoin::network::constants::Network;
+
+    use super::*;
+    use crate::fake::bitcoind::FakeBitcoind;
+    use crate::index::compute_script_hash;
+    use crate::store::{Family, ReadStore};
+
+    #[test]
+    fn test_block_iter() {
+        let magic = Network::Regtest.magic();
+        let block = genesis_block(Network::Regtest);
+        let mut blob = vec![];
+        for padding in &[0, 3, 0] {
+            blob.extend(vec![0u8; *padding]); // e.g. preallocated (but unused) space
+            blob.extend(serialize(&magic));
+            blob.extend(serialize(&(serialize(&block).len() as u32)));
+            blob.extend(serialize(&block));
+        }
+        blob.truncate(blob.len() - 1); // the last block is being written
+        let parse = |blob: &[u8], xor_key: Option<&Vec<u8>>| -> Vec<Block> {
+            BlockIter::new(blob, magic, xor_key)
+                .map(|block| block.unwrap())
+                .collect()
+        };
+        assert_eq!(parse(&blob, None), vec![block.clone(), block.clone()]);
+
+        // the key is applied by file offset, so it may not be aligned to the blocks
+        let key = vec![0xff, 0x00, 0x5a];
+        xor(&mut blob, &key, 0);
+        assert_eq!(parse(&blob, Some(&key)), vec![block.clone(), block]);
+    }
+
+    // Writes all the daemon's blocks into a blk*.dat file, returning their hashes.
+    fn write_blk_file(daemon: &Daemon, path: &Path, xor_key: Option<&[u8]>) -> Vec<Sha256dHash> {
+        let blockhashes: Vec<Sha256dHash> = load_headers(daemon)
+            .unwrap()
+            .iter()
+            .map(|h| *h.hash())
+            .collect();
+        let mut blob = vec![];
+        for block in daemon.getblocks(&blockhashes).unwrap() {
+            let block = serialize(&block);
+            blob.extend(serialize(&daemon.magic()));
+            blob.extend(serialize(&(block.len() as u32)));
+            blob.extend(block);
+        }
+        if let Some(key) = xor_key {
+            xor(&mut blob, key, 0);
+        }
+        fs::write(path, &blob).unwrap();
+        blockhashes
+    }
+
+    #[test]
+    fn test_obfuscated_blk_files() {
+        let bitcoind = FakeBitcoind::start();
+        for _ in 0..3 {
+            bitcoind.mine(vec![]);
+        }
+        let mut config = bitcoind.config("xor");
+        config.daemon_dir = config.db_path.with_extension("bitcoind");
+        let blocks_dir = config.daemon_dir.join("blocks");
+        fs::create_dir_all(&blocks_dir).unwrap();
+        let metrics = Metrics::new(config.monitoring_addr);
+        let daemon = FakeBitcoind::daemon(&config);
+
+        // write all the blocks into an obfuscated blk*.dat file
+        let key = b"\x01\x23\x45\x67\x89\xab\xcd\xef".to_vec();
+        let blockhashes = write_blk_file(&daemon, &blocks_dir.join("blk00000.dat"), Some(&key));
+
+        // an all-zeros key means no obfuscation
+        fs::write(blocks_dir.join("xor.dat"), [0u8; 8]).unwrap();
+        assert_eq!(daemon.list_blk_files().unwrap().xor_key, None);
+        fs::write(blocks_dir.join("xor.dat"), &key).unwrap();
+        let blk_files = daemon.list_blk_files().unwrap();
+        assert_eq!(blk_files.paths, vec![blocks_dir.join("blk00000.dat")]);
+        assert_eq!(blk_files.xor_key, Some(key));
+
+        let store = DBStore::open(
+            &config.db_path,
+            /*low_memory=*/ false,
+            config.db_cache_size,
+        )
+        .unwrap();
+        let store = index_blk_files(&daemon, 1, &metrics, store, false, false).unwrap();
+        assert_eq!(
+            read_indexed_blockhashes(&store),
+            blockhashes.into_iter().collect()
+        );
+        drop(store);
+        fs::remove_dir_all(&config.db_path).unwrap();
+        fs::remove_dir_all(&config.daemon_dir).unwrap();
+    }
+
+    #[test]
+    fn test_bulk_utxos() {
+        let bitcoind = FakeBitcoind::start();
+        let funding = bitcoind.mine_to(b"alice", vec![]);
+        let mut config = bitcoind.config("bulk-utxos");
+        config.daemon_dir = config.db_path.with_extension("bitcoind");
+        let blocks_dir = config.daemon_dir.join("blocks");
+        fs::create_dir_all(&blocks_dir).unwrap();
+        let metrics = Metrics::new(config.monitoring_addr);
+        let daemon = FakeBitcoind::daemon(&config);
+        let txid = daemon.getblock(&funding).unwrap().txdata[0].txid();
+        let payment = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint { txid, vout: 0 },
+                script_sig: Script::new(),
+                sequence: 0xffff_ffff,
+                witness: vec![],
+            }],
+            output: vec![TxOut {
+                value: 50,
+                script_pubkey: Script::from(b"bob".to_vec()),
+            }],
+        };
+        bitcoind.mine(vec![payment]);
+        for _ in 0..MAX_REORG_DEPTH {
+            bitcoind.mine(vec![]);
+        }
+        write_blk_file(&daemon, &blocks_dir.join("blk00000.dat"), None);
+
+        let store = DBStore::open(
+            &config.db_path,
+            /*low_memory=*/ false,
+            config.db_cache_size,
+        )
+        .unwrap();
+        let store = index_blk_files(&daemon, 1, &metrics, store, true, false).unwrap();
+        // the latest blocks are left for JSONRPC indexing
+        assert_eq!(read_indexed_blockhashes(&store).len(), 3);
+        let utxos = |script: &[u8]| {
+            let prefix = [b"U", &compute_script_hash(script)[..]].concat();
+            store.scan(Family::Utxo, &prefix).count()
+        };
+        assert_eq!(utxos(b"alice"), 0); // spent
+        assert_eq!(utxos(b"bob"), 1);
+        assert_eq!(store.scan(Family::Utxo, b"S").count(), 0); // the spent markers are deleted
+        assert_eq!(store.scan(Family::Utxo, b"P").count(), 2 + 1); // the unspent coinbases', and bob's
+        drop(store);
+        fs::remove_dir_all(&config.db_path).unwrap();
+        fs::remove_dir_all(&config.daemon_dir).unwrap();
+    }
+}
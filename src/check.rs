@@ -0,0 +1,297 @@
+use bitcoin::blockdata::block::Block;
+use bitcoin::consensus::encode::deserialize;
+use bitcoin::util::hash::{BitcoinHash, Sha256dHash};
+use hex;
+
+use crate::daemon::Daemon;
+use crate::errors::*;
+use crate::index::{
+    block_undo_row, has_tx_store, has_utxo_index, index_block, last_indexed_block,
+    read_block_headers, TxRow, MAX_REORG_DEPTH,
+};
+use crate::store::{DBStore, Family, ReadStore, Row, WriteStore};
+use crate::util::{HeaderEntry, HeaderList};
+
+/// Checks the index against the daemon's best chain, reporting (and counting) the problems found.
+pub struct Checker<'a> {
+    store: &'a DBStore,
+    daemon: &'a Daemon,
+    best_headers: HeaderList,
+    batch_size: usize,
+    problems: usize,
+}
+
+impl<'a> Checker<'a> {
+    pub fn new(store: &'a DBStore, daemon: &'a Daemon, batch_size: usize) -> Result<Self> {
+        let tip = daemon.getbestblockhash()?;
+        let mut best_headers = HeaderList::empty();
+        let new_headers = best_headers.order(daemon.get_new_headers(&best_headers, &tip)?);
+        best_headers.apply(new_headers);
+        Ok(Checker {
+            store,
+            daemon,
+            best_headers,
+            batch_size,
+            problems: 0,
+        })
+    }
+
+    pub fn problems(&self) -> usize {
+        self.problems
+    }
+
+    fn report(&mut self, problem: String) {
+        error!("{}", problem);
+        self.problems += 1;
+    }
+
+    /// Walks the `B` rows back from the `L` row (like `Index::load`),
+    /// returning the height of the last indexed block (if it is in the best chain).
+    pub fn check_headers(&mut self) -> Option<usize> {
+        let mut headers = read_block_headers(self.store);
+        info!("found {} indexed blocks", headers.len());
+        let null_hash = Sha256dHash::default();
+        let latest_blockhash: Sha256dHash = match self.store.get(Family::Default, b"L") {
+            Some(value) => match deserialize(&value) {
+                Ok(blockhash) => blockhash,
+                Err(e) => {
+                    self.report(format!("invalid L row {}: {}", hex::encode(&value), e));
+                    null_hash
+                }
+            },
+            None => {
+                if !headers.is_empty() {
+                    self.report("missing L row (interrupted initial indexing?)".to_owned());
+                }
+                null_hash
+            }
+        };
+        let mut blockhash = latest_blockhash;
+        let mut depth = 0;
+        let mut stale = 0;
+        while blockhash != null_hash {
+            let header = match headers.remove(&blockhash) {
+                Some(header) => header,
+                None => {
+                    self.report(format!(
+                        "missing B row of block {} ({} blocks below the L row)",
+                        blockhash, depth
+                    ));
+                    break;
+                }
+            };
+            if self.best_headers.header_by_blockhash(&blockhash).is_none() {
+                stale += 1;
+            }
+            blockhash = header.prev_blockhash;
+            depth += 1;
+        }
+        if stale > 0 {
+            self.report(format!(
+                "{} blocks below the L row are not in the best chain",
+                stale
+            ));
+        }
+        // the rest are not connected to the L row
+        let disconnected = headers
+            .keys()
+            .filter(|blockhash| self.best_headers.header_by_blockhash(blockhash).is_some())
+            .count();
+        if disconnected > 0 {
+            self.report(format!(
+                "{} blocks of the best chain are indexed above the L row",
+                disconnected
+            ));
+        }
+        if headers.len() > disconnected {
+            // may be left by reorgs, and are ignored
+            warn!(
+                "{} indexed blocks are not in the best chain",
+                headers.len() - disconnected
+            );
+        }
+        self.best_headers
+            .header_by_blockhash(&latest_blockhash)
+            .map(|header| header.height())
+    }
+
+    fn best_chain(&self, from: usize, to: usize) -> Result<Vec<HeaderEntry>> {
+        if from > to {
+            bail!("invalid height range: {}..{}", from, to);
+        }
+        if to >= self.best_headers.len() {
+            bail!(
+                "height {} is above the best chain tip ({})",
+                to,
+                self.best_headers.len() - 1
+            );
+        }
+        Ok(self
+            .best_headers
+            .iter()
+            .skip(from)
+            .take(to + 1 - from)
+            .cloned()
+            .collect())
+    }
+
+    // Fetches the best chain blocks at the specified heights (inclusive), in batches.
+    fn for_each_block<F>(&mut self, from: usize, to: usize, mut func: F) -> Result<()>
+    where
+        F: FnMut(&mut Self, &Block, usize),
+    {
+        for chunk in self.best_chain(from, to)?.chunks(self.batch_size) {
+            let blockhashes: Vec<Sha256dHash> = chunk.iter().map(|h| *h.hash()).collect();
+            let blocks = self.daemon.getblocks(&blockhashes)?;
+            for (block, header) in blocks.iter().zip(chunk) {
+                func(self, block, header.height());
+            }
+            info!(
+                "processed blocks up to height {}",
+                chunk.last().unwrap().height()
+            );
+        }
+        Ok(())
+    }
+
+    /// Checks that the rows of the specified blocks exist: all the `B` and `T` rows, and every
+    /// `sample_interval`-th row of the other types (e.g. `O` and `I` rows). The UTXO rows are
+    /// not checked, since they are deleted when their outputs are spent.
+    pub fn check_blocks(&mut self, from: usize, to: usize, sample_interval: usize) -> Result<()> {
+        let utxos = has_utxo_index(self.store);
+        let txs = has_tx_store(self.store);
+        let mut count = 0;
+        self.for_each_block(from, to, |checker, block, height| {
+            let coinbase_key = TxRow::new(&block.txdata[0].txid(), height as u32)
+                .to_row()
+                .key;
+            for row in index_block(block, height, utxos, txs) {
+                match row.key[0] {
+                    b'U' | b'P' => continue,
+                    b'B' | b'T' => (),
+                    _ => {
+                        count += 1;
+                        if count % sample_interval != 0 {
+                            continue;
+                        }
+                    }
+                }
+                // duplicate coinbase transactions (see BIP-30) are indexed at either height
+                let check_value = row.key != coinbase_key;
+                checker.check_row(&row, height, check_value);
+            }
+        })
+    }
+
+    fn check_row(&mut self, row: &Row, height: usize, check_value: bool) {
+        match self.store.get(row.family(), &row.key) {
+            None => self.report(format!(
+                "missing {} row {} of block at height {}",
+                row.key[0] as char,
+                hex::encode(&row.key),
+                height
+            )),
+            Some(ref value) if check_value && *value != row.value => self.report(format!(
+                "invalid {} row {} of block at height {}: {} (instead of {})",
+                row.key[0] as char,
+                hex::encode(&row.key),
+                height,
+                hex::encode(value),
+                hex::encode(&row.value)
+            )),
+            Some(_) => (),
+        }
+    }
+
+    /// Reindexes the specified blocks, and moves the `L` row to the last block of the best chain
+    /// that has all its previous blocks indexed.
+    /// Note that the rows of blocks that are not in the best chain are left as is, and that the
+    /// UTXO rows (and the undo data of the UTXO index) are not repaired, since the spent outputs
+    /// can't be found without indexing all the following blocks.
+    pub fn repair(&mut self, from: usize, to: usize) -> Result<()> {
+        let utxos = has_utxo_index(self.store);
+        let txs = has_tx_store(self.store);
+        let best_height = self.best_headers.len() - 1;
+        if utxos {
+            warn!("the UTXO rows are not repaired");
+        }
+        self.for_each_block(from, to, |checker, block, height| {
+            let mut rows = index_block(block, height, /*utxos=*/ false, txs);
+            if !utxos && height + MAX_REORG_DEPTH > best_height {
+                rows.push(block_undo_row(&block.bitcoin_hash(), height, &rows, &[]));
+            }
+            checker.store.write(rows);
+        })?;
+        let indexed = read_block_headers(self.store);
+        let last_header = self
+            .best_headers
+            .iter()
+            .take_while(|h| indexed.contains_key(h.hash()))
+            .last();
+        if let Some(header) = last_header {
+            info!("moving L row to {:?}", header);
+            self.store.write(vec![last_indexed_block(header.hash())]);
+        }
+        self.store.flush();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::fake::bitcoind::FakeBitcoind;
+    use crate::index::Index;
+    use crate::metrics::Metrics;
+    use crate::signal::Waiter;
+
+    #[test]
+    fn test_check_and_repair() {
+        let bitcoind = FakeBitcoind::start();
+        for _ in 0..4 {
+            bitcoind.mine_to(b"alice", vec![]);
+        }
+        let config = bitcoind.config("check");
+        let signal = Waiter::new();
+        let metrics = Metrics::new(config.monitoring_addr);
+        let daemon = FakeBitcoind::daemon(&config);
+        let store = DBStore::open(
+            &config.db_path,
+            /*low_memory=*/ false,
+            config.db_cache_size,
+        )
+        .unwrap();
+        let index = Index::load(
+            &store, &daemon, &metrics, 2, /*utxos=*/ true, /*txs=*/ false,
+        )
+        .unwrap();
+        index.update(&store, &signal).unwrap();
+
+        let mut checker = Checker::new(&store, &daemon, 2).unwrap();
+        assert_eq!(checker.check_headers(), Some(4));
+        checker.check_blocks(0, 4, 1).unwrap();
+        assert_eq!(checker.problems(), 0);
+
+        // lose the rows of the block at height 2
+        let blockhash = *checker.best_headers.header_by_height(2).unwrap().hash();
+        let block = daemon.getblock(&blockhash).unwrap();
+        let keys = index_block(&block, 2, /*utxos=*/ false, /*txs=*/ false)
+            .into_iter()
+            .map(|row| row.key)
+            .collect();
+        store.delete_and_write(keys, vec![]);
+        assert_eq!(checker.check_headers(), Some(4));
+        assert_eq!(checker.problems(), 2); // broken L chain, and disconnected blocks above it
+        checker.check_blocks(0, 4, 1).unwrap();
+        assert_eq!(checker.problems(), 2 + 3); // missing B, T and O rows
+
+        checker.repair(2, 2).unwrap();
+        let mut checker = Checker::new(&store, &daemon, 2).unwrap();
+        assert_eq!(checker.check_headers(), Some(4));
+        checker.check_blocks(0, 4, 1).unwrap();
+        assert_eq!(checker.problems(), 0);
+        fs::remove_dir_all(&config.db_path).unwrap();
+    }
+}
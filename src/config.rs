@@ -1,5 +1,5 @@
 use bitcoin::network::constants::Network;
-use clap::{App, Arg};
+use clap::{App, Arg, ArgMatches};
 use dirs::home_dir;
 use num_cpus;
 use std::fs;
@@ -21,17 +21,37 @@ pub struct Config {
     pub daemon_rpc_addr: SocketAddr,
     pub cookie: Option<String>,
     pub electrum_rpc_addr: SocketAddr,
+    pub electrum_ssl_addr: Option<SocketAddr>,
+    pub ssl_certfile: Option<PathBuf>,
+    pub ssl_keyfile: Option<PathBuf>,
+    pub electrum_ws_addr: Option<SocketAddr>,
+    pub electrum_public_hosts: Vec<String>,
+    pub electrum_peer_discovery: bool,
+    pub electrum_peers: Vec<(String, u16)>,
     pub monitoring_addr: SocketAddr,
     pub jsonrpc_import: bool,
     pub index_batch_size: usize,
+    pub index_utxos: bool,
+    pub store_txs: bool,
     pub bulk_index_threads: usize,
+    pub rpc_threads: usize,
     pub tx_cache_size: usize,
+    pub db_cache_size: usize,
     pub txid_limit: usize,
+    pub batch_limit: usize,
+    pub connection_subscription_limit: usize,
+    pub server_subscription_limit: usize,
     pub server_banner: String,
 }
 
 impl Config {
     pub fn from_args() -> Config {
+        Config::from_args_with(vec![]).0
+    }
+
+    /// Parses the common flags together with the specified tool-specific ones,
+    /// returning the latter's matches too.
+    pub fn from_args_with(extra_args: Vec<Arg<'static, 'static>>) -> (Config, ArgMatches<'static>) {
         let m = App::new("Electrum Rust Server")
             .version(crate_version!())
             .arg(
@@ -75,6 +95,50 @@ impl Config {
                     .help("Electrum server JSONRPC 'addr:port' to listen on (default: '127.0.0.1:50001' for mainnet, '127.0.0.1:60001' for testnet and '127.0.0.1:60401' for regtest)")
                     .takes_value(true),
             )
+            .arg(
+                Arg::with_name("electrum_ssl_addr")
+                    .long("electrum-ssl-addr")
+                    .help("Electrum server TLS-encrypted JSONRPC 'addr:port' to listen on, if a certificate is provided (default: '127.0.0.1:50002' for mainnet, '127.0.0.1:60002' for testnet and '127.0.0.1:60402' for regtest)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("ssl_certfile")
+                    .long("ssl-certfile")
+                    .help("PEM-encoded TLS certificate chain, reloaded when changed (enables the TLS listener)")
+                    .takes_value(true)
+                    .requires("ssl_keyfile"),
+            )
+            .arg(
+                Arg::with_name("ssl_keyfile")
+                    .long("ssl-keyfile")
+                    .help("PEM-encoded TLS private key, reloaded when changed")
+                    .takes_value(true)
+                    .requires("ssl_certfile"),
+            )
+            .arg(
+                Arg::with_name("electrum_ws_addr")
+                    .long("electrum-ws-addr")
+                    .help("Electrum server WebSocket 'addr:port' to listen on (disabled by default)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("electrum_public_hosts")
+                    .long("electrum-public-hosts")
+                    .help("Comma-separated host names this server is reachable at, reported via 'server.features'")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("electrum_peer_discovery")
+                    .long("electrum-peer-discovery")
+                    .help("Discover other Electrum servers, and announce this one to them (if public hosts are set)"),
+            )
+            .arg(
+                Arg::with_name("electrum_peers")
+                    .long("electrum-peers")
+                    .help("Comma-separated 'host:port' Electrum servers (TCP), to start peer discovery from")
+                    .takes_value(true)
+                    .requires("electrum_peer_discovery"),
+            )
             .arg(
                 Arg::with_name("daemon_rpc_addr")
                     .long("daemon-rpc-addr")
@@ -98,30 +162,71 @@ impl Config {
                     .help("Number of blocks to get in one JSONRPC request from bitcoind")
                     .default_value("100"),
             )
+            .arg(
+                Arg::with_name("index_utxos")
+                    .long("index-utxos")
+                    .help("Index unspent outputs, to answer balance and unspent outputs queries without loading transactions (requires indexing from scratch)"),
+            )
+            .arg(
+                Arg::with_name("store_txs")
+                    .long("store-txs")
+                    .help("Store confirmed transactions at the index, to load them without bitcoind's JSONRPC (requires indexing from scratch)"),
+            )
             .arg(
                 Arg::with_name("bulk_index_threads")
                     .long("bulk-index-threads")
                     .help("Number of threads used for bulk indexing (default: use the # of CPUs)")
                     .default_value("0")
             )
+            .arg(
+                Arg::with_name("rpc_threads")
+                    .long("rpc-threads")
+                    .help("Number of threads used for handling Electrum RPC requests (default: use the # of CPUs)")
+                    .default_value("0")
+            )
             .arg(
                 Arg::with_name("tx_cache_size")
                     .long("tx-cache-size")
                     .help("Number of transactions to keep in for query LRU cache")
                     .default_value("10000")  // should be enough for a small wallet.
             )
+            .arg(
+                Arg::with_name("db_cache_mb")
+                    .long("db-cache-mb")
+                    .help("Size of the RocksDB block cache (in MB), shared by all the row types")
+                    .default_value("512")
+            )
             .arg(
                 Arg::with_name("txid_limit")
                     .long("txid-limit")
                     .help("Number of transactions to lookup before returning an error, to prevent \"too popular\" addresses from causing the RPC server to get stuck (0 - disable the limit)")
                     .default_value("100")  // should take a few seconds on a HDD
             )
+            .arg(
+                Arg::with_name("batch_limit")
+                    .long("batch-limit")
+                    .help("Maximum number of requests in a single JSON-RPC batch (0 - disable the limit)")
+                    .default_value("1000")
+            )
+            .arg(
+                Arg::with_name("connection_subscription_limit")
+                    .long("connection-subscription-limit")
+                    .help("Maximum number of script hash subscriptions per connection (0 - disable the limit)")
+                    .default_value("50000")
+            )
+            .arg(
+                Arg::with_name("server_subscription_limit")
+                    .long("server-subscription-limit")
+                    .help("Maximum number of script hash subscriptions for all connections (0 - disable the limit)")
+                    .default_value("1000000")
+            )
             .arg(
                 Arg::with_name("server_banner")
                     .long("server-banner")
                     .help("The banner to be shown in the Electrum console")
                     .default_value("Welcome to electrs (Electrum Rust Server)!")
             )
+            .args(&extra_args)
             .get_matches();
 
         let network_name = m.value_of("network").unwrap_or("mainnet");
@@ -144,6 +249,11 @@ impl Config {
             Network::Testnet => 60001,
             Network::Regtest => 60401,
         };
+        let default_electrum_ssl_port = match network_type {
+            Network::Bitcoin => 50002,
+            Network::Testnet => 60002,
+            Network::Regtest => 60402,
+        };
         let default_monitoring_port = match network_type {
             Network::Bitcoin => 4224,
             Network::Testnet => 14224,
@@ -160,6 +270,40 @@ impl Config {
             .unwrap_or(&format!("127.0.0.1:{}", default_electrum_port))
             .parse()
             .expect("invalid Electrum RPC address");
+        let ssl_certfile = m.value_of("ssl_certfile").map(PathBuf::from);
+        let ssl_keyfile = m.value_of("ssl_keyfile").map(PathBuf::from);
+        let electrum_ssl_addr: Option<SocketAddr> = ssl_certfile.as_ref().map(|_| {
+            m.value_of("electrum_ssl_addr")
+                .unwrap_or(&format!("127.0.0.1:{}", default_electrum_ssl_port))
+                .parse()
+                .expect("invalid Electrum TLS address")
+        });
+        let electrum_ws_addr: Option<SocketAddr> = m
+            .value_of("electrum_ws_addr")
+            .map(|addr| addr.parse().expect("invalid Electrum WebSocket address"));
+        let electrum_public_hosts: Vec<String> = m
+            .value_of("electrum_public_hosts")
+            .map(|hosts| {
+                hosts
+                    .split(',')
+                    .map(|host| host.trim().to_owned())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let electrum_peers: Vec<(String, u16)> = m
+            .value_of("electrum_peers")
+            .map(|peers| {
+                peers
+                    .split(',')
+                    .map(|peer| {
+                        let mut parts = peer.trim().rsplitn(2, ':');
+                        let port = parts.next().unwrap().parse().expect("invalid peer port");
+                        let host = parts.next().expect("missing peer port").to_owned();
+                        (host, port)
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
         let monitoring_addr: SocketAddr = m
             .value_of("monitoring_addr")
             .unwrap_or(&format!("127.0.0.1:{}", default_monitoring_port))
@@ -193,6 +337,10 @@ impl Config {
         if bulk_index_threads == 0 {
             bulk_index_threads = num_cpus::get();
         }
+        let mut rpc_threads = value_t_or_exit!(m, "rpc_threads", usize);
+        if rpc_threads == 0 {
+            rpc_threads = num_cpus::get();
+        }
         let config = Config {
             log,
             network_type,
@@ -201,16 +349,34 @@ impl Config {
             daemon_rpc_addr,
             cookie,
             electrum_rpc_addr,
+            electrum_ssl_addr,
+            ssl_certfile,
+            ssl_keyfile,
+            electrum_ws_addr,
+            electrum_public_hosts,
+            electrum_peer_discovery: m.is_present("electrum_peer_discovery"),
+            electrum_peers,
             monitoring_addr,
             jsonrpc_import: m.is_present("jsonrpc_import"),
             index_batch_size: value_t_or_exit!(m, "index_batch_size", usize),
+            index_utxos: m.is_present("index_utxos"),
+            store_txs: m.is_present("store_txs"),
             bulk_index_threads,
+            rpc_threads,
             tx_cache_size: value_t_or_exit!(m, "tx_cache_size", usize),
+            db_cache_size: value_t_or_exit!(m, "db_cache_mb", usize) << 20,
             txid_limit: value_t_or_exit!(m, "txid_limit", usize),
+            batch_limit: value_t_or_exit!(m, "batch_limit", usize),
+            connection_subscription_limit: value_t_or_exit!(
+                m,
+                "connection_subscription_limit",
+                usize
+            ),
+            server_subscription_limit: value_t_or_exit!(m, "server_subscription_limit", usize),
             server_banner: value_t_or_exit!(m, "server_banner", String),
         };
         eprintln!("{:?}", config);
-        config
+        (config, m)
     }
 
     pub fn cookie_getter(&self) -> Arc<CookieGetter> {
@@ -9,9 +9,10 @@ use glob;
 use hex;
 use serde_json::{from_str, from_value, Value};
 use std::collections::{HashMap, HashSet};
+use std::fs;
 use std::io::{BufRead, BufReader, Lines, Write};
 use std::net::{SocketAddr, TcpStream};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
@@ -20,6 +21,30 @@ use crate::metrics::{HistogramOpts, HistogramVec, Metrics};
 use crate::signal::Waiter;
 use crate::util::HeaderList;
 
+/// The blk*.dat files of the daemon, which may be obfuscated (see `read_xor_key`).
+pub struct BlkFiles {
+    pub paths: Vec<PathBuf>,
+    pub xor_key: Option<Vec<u8>>, // `None` if the files are not obfuscated
+}
+
+// Newer bitcoind versions obfuscate the blk*.dat files by XOR-ing them with a random key,
+// stored at `blocks/xor.dat` (a missing file, or an all-zeros key, means no obfuscation).
+fn read_xor_key(blocks_dir: &Path) -> Result<Option<Vec<u8>>> {
+    let path = blocks_dir.join("xor.dat");
+    if !path.exists() {
+        return Ok(None);
+    }
+    let key = fs::read(&path).chain_err(|| format!("failed to read {:?}", path))?;
+    if key.is_empty() {
+        bail!("empty XOR key at {:?}", path);
+    }
+    Ok(if key.iter().all(|b| *b == 0) {
+        None
+    } else {
+        Some(key)
+    })
+}
+
 fn parse_hash(value: &Value) -> Result<Sha256dHash> {
     Ok(Sha256dHash::from_hex(
         value
@@ -65,7 +90,11 @@ fn parse_jsonrpc_reply(mut reply: Value, method: &str, expected_id: u64) -> Resu
                     match code {
                         // RPC_IN_WARMUP -> retry by later reconnection
                         -28 => bail!(ErrorKind::Connection(err.to_string())),
-                        _ => (),
+                        _ => {
+                            let msg = err.get("message").and_then(Value::as_str).unwrap_or("");
+                            let e = Error::from(ErrorKind::Daemon(code, msg.to_owned()));
+                            return Err(e).chain_err(|| format!("{} RPC failed", method));
+                        }
                     }
                 }
                 bail!("{} RPC error: {}", method, err);
@@ -354,17 +383,20 @@ impl Daemon {
         })
     }
 
-    pub fn list_blk_files(&self) -> Result<Vec<PathBuf>> {
-        let mut path = self.daemon_dir.clone();
-        path.push("blocks");
-        path.push("blk*.dat");
+    pub fn list_blk_files(&self) -> Result<BlkFiles> {
+        let blocks_dir = self.daemon_dir.join("blocks");
+        let path = blocks_dir.join("blk*.dat");
         info!("listing block files at {:?}", path);
         let mut paths: Vec<PathBuf> = glob::glob(path.to_str().unwrap())
             .chain_err(|| "failed to list blk*.dat files")?
             .map(|res| res.unwrap())
             .collect();
         paths.sort();
-        Ok(paths)
+        let xor_key = read_xor_key(&blocks_dir)?;
+        if let Some(ref key) = xor_key {
+            info!("block files are obfuscated (XOR key: {})", hex::encode(key));
+        }
+        Ok(BlkFiles { paths, xor_key })
     }
 
     pub fn magic(&self) -> u32 {
@@ -15,5 +15,40 @@ error_chain! {
             description("Interruption by external signal")
             display("Iterrupted by SIG{:?}", signal)
         }
+
+        InvalidRequest(msg: String) {
+            description("Invalid request")
+            display("invalid request: {}", msg)
+        }
+
+        MethodNotFound(method: String) {
+            description("Method not found")
+            display("unknown method {}", method)
+        }
+
+        BadRequest(msg: String) {
+            description("Bad request")
+            display("{}", msg)
+        }
+
+        Daemon(code: i64, msg: String) {
+            description("Daemon error")
+            display("daemon error {}: {}", code, msg)
+        }
+
+        Rejected(code: i64, msg: String) {
+            description("Transaction rejected")
+            display("transaction rejected by daemon ({}): {}", code, msg)
+        }
+
+        TooManyTxs(count: usize) {
+            description("Too many transactions")
+            display("{}+ transactions found, query may take a long time", count)
+        }
+
+        ResourceLimit(msg: String) {
+            description("Resource limit exceeded")
+            display("{}", msg)
+        }
     }
 }
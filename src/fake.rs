@@ -1,37 +1,315 @@
-use crate::store::{ReadStore, Row, WriteStore};
+use std::iter;
+
+use crate::store::{Family, ReadStore, Row, RowIterator, WriteStore};
 use crate::util::Bytes;
 
 pub struct FakeStore;
 
 impl ReadStore for FakeStore {
-    fn get(&self, _key: &[u8]) -> Option<Bytes> {
+    fn get(&self, _family: Family, _key: &[u8]) -> Option<Bytes> {
         None
     }
-    fn scan(&self, _prefix: &[u8]) -> Vec<Row> {
-        vec![]
+    fn scan<'a>(&'a self, _family: Family, _prefix: &[u8]) -> RowIterator<'a> {
+        Box::new(iter::empty())
+    }
+    fn scan_from<'a>(&'a self, _family: Family, _prefix: &[u8], _start: &[u8]) -> RowIterator<'a> {
+        Box::new(iter::empty())
     }
 }
 
 impl WriteStore for FakeStore {
     fn write(&self, _rows: Vec<Row>) {}
+    fn delete_and_write(&self, _keys: Vec<Bytes>, _rows: Vec<Row>) {}
     fn flush(&self) {}
 }
 
+/// A minimal bitcoind JSONRPC server, serving a chain of synthetic blocks (for regtest-style tests).
+#[cfg(test)]
+pub mod bitcoind {
+    use bitcoin::blockdata::block::{Block, BlockHeader};
+    use bitcoin::blockdata::script::Script;
+    use bitcoin::blockdata::transaction::{OutPoint, Transaction, TxIn, TxOut};
+    use bitcoin::consensus::encode::serialize;
+    use bitcoin::network::constants::Network;
+    use bitcoin::util::hash::{BitcoinHash, Sha256dHash};
+    use hex;
+    use serde_json::{self, Value};
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
+    use std::{env, fs, process};
+
+    use crate::config::Config;
+    use crate::daemon::Daemon;
+    use crate::metrics::Metrics;
+    use crate::signal::Waiter;
+    use crate::util::spawn_thread;
+
+    #[derive(Default)]
+    struct Chain {
+        active: Vec<Sha256dHash>,            // by height
+        blocks: HashMap<Sha256dHash, Block>, // including the orphaned ones
+        mempool: HashMap<Sha256dHash, Transaction>,
+        nonce: u32,
+    }
+
+    impl Chain {
+        fn tip(&self) -> Sha256dHash {
+            self.active.last().cloned().unwrap_or_default()
+        }
+
+        fn height(&self, blockhash: &Sha256dHash) -> Option<usize> {
+            self.active.iter().position(|hash| hash == blockhash)
+        }
+
+        fn block(&self, value: &Value) -> Option<&Block> {
+            let blockhash = Sha256dHash::from_hex(value.as_str()?).ok()?;
+            self.blocks.get(&blockhash)
+        }
+
+        fn txid(params: &[Value]) -> Option<Sha256dHash> {
+            Sha256dHash::from_hex(params.get(0)?.as_str()?).ok()
+        }
+
+        fn handle(&self, method: &str, params: &[Value]) -> Option<Value> {
+            let hex_of = |hash: &Sha256dHash| json!(hash.be_hex_string());
+            Some(match method {
+                "getnetworkinfo" => json!({"version": 170000, "subversion": "/FakeBitcoind/"}),
+                "getblockchaininfo" => json!({
+                    "chain": "regtest",
+                    "blocks": self.active.len() - 1,
+                    "headers": self.active.len() - 1,
+                    "bestblockhash": self.tip().be_hex_string(),
+                    "pruned": false,
+                    "initialblockdownload": false,
+                }),
+                "getbestblockhash" => hex_of(&self.tip()),
+                "getblockhash" => hex_of(self.active.get(params.get(0)?.as_u64()? as usize)?),
+                "getblockheader" => {
+                    let block = self.block(params.get(0)?)?;
+                    match params.get(1).and_then(Value::as_bool) {
+                        Some(false) => json!(hex::encode(serialize(&block.header))),
+                        _ => json!({"height": self.height(&block.bitcoin_hash())?}),
+                    }
+                }
+                "getblock" => json!(hex::encode(serialize(self.block(params.get(0)?)?))),
+                "getrawtransaction" => {
+                    let txid = Chain::txid(params)?;
+                    let txn = match params.get(2) {
+                        Some(blockhash) => {
+                            let block = self.block(blockhash)?;
+                            block.txdata.iter().find(|txn| txn.txid() == txid)?
+                        }
+                        None => self.mempool.get(&txid)?,
+                    };
+                    json!(hex::encode(serialize(txn)))
+                }
+                "getrawmempool" => json!(self.mempool.keys().map(hex_of).collect::<Vec<_>>()),
+                "getmempoolentry" => {
+                    let txid = Chain::txid(params)?;
+                    let txn = self.mempool.get(&txid)?;
+                    json!({"fee": 0.000_01, "size": serialize(txn).len()})
+                }
+                _ => return None,
+            })
+        }
+    }
+
+    pub struct FakeBitcoind {
+        addr: SocketAddr,
+        chain: Arc<Mutex<Chain>>,
+    }
+
+    impl FakeBitcoind {
+        /// Starts serving a chain containing only a genesis block.
+        pub fn start() -> FakeBitcoind {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let bitcoind = FakeBitcoind {
+                addr: listener.local_addr().unwrap(),
+                chain: Arc::new(Mutex::new(Chain::default())),
+            };
+            bitcoind.mine(vec![]);
+            let chain = bitcoind.chain.clone();
+            spawn_thread("fake_bitcoind", move || {
+                for stream in listener.incoming() {
+                    let chain = chain.clone();
+                    spawn_thread("fake_bitcoind_conn", move || serve(stream.unwrap(), chain));
+                }
+            });
+            bitcoind
+        }
+
+        /// Returns a config for using this daemon, with a new (per-test) DB directory.
+        pub fn config(&self, test_name: &str) -> Config {
+            let db_path =
+                env::temp_dir().join(format!("electrs-{}-test-{}", test_name, process::id()));
+            let _ = fs::remove_dir_all(&db_path);
+            Config {
+                log: stderrlog::new(),
+                network_type: Network::Regtest,
+                db_path,
+                daemon_dir: env::temp_dir(),
+                daemon_rpc_addr: self.addr,
+                cookie: Some("user:password".to_owned()),
+                electrum_rpc_addr: "127.0.0.1:0".parse().unwrap(),
+                electrum_ssl_addr: None,
+                ssl_certfile: None,
+                ssl_keyfile: None,
+                electrum_ws_addr: None,
+                electrum_public_hosts: vec![],
+                electrum_peer_discovery: false,
+                electrum_peers: vec![],
+                monitoring_addr: "127.0.0.1:0".parse().unwrap(),
+                jsonrpc_import: true,
+                index_batch_size: 2,
+                index_utxos: true,
+                store_txs: false,
+                bulk_index_threads: 1,
+                rpc_threads: 1,
+                tx_cache_size: 100,
+                db_cache_size: 1 << 20,
+                txid_limit: 0,
+                batch_limit: 0,
+                connection_subscription_limit: 0,
+                server_subscription_limit: 0,
+                server_banner: "test".to_owned(),
+            }
+        }
+
+        /// Connects the daemon at `config` (e.g. one returned by `config()`).
+        pub fn daemon(config: &Config) -> Daemon {
+            Daemon::new(
+                &config.daemon_dir,
+                config.daemon_rpc_addr,
+                config.cookie_getter(),
+                config.network_type,
+                Waiter::new(),
+                &Metrics::new(config.monitoring_addr),
+            )
+            .unwrap()
+        }
+
+        pub fn addr(&self) -> SocketAddr {
+            self.addr
+        }
+
+        /// Mines a block (with a coinbase paying to `script`) on top of the current tip.
+        pub fn mine_to(&self, script: &[u8], mut txdata: Vec<Transaction>) -> Sha256dHash {
+            let mut chain = self.chain.lock().unwrap();
+            chain.nonce += 1;
+            let coinbase = Transaction {
+                version: 1,
+                lock_time: 0,
+                input: vec![TxIn {
+                    previous_output: OutPoint::null(),
+                    script_sig: Script::from(serialize(&chain.nonce)),
+                    sequence: 0xffff_ffff,
+                    witness: vec![],
+                }],
+                output: vec![TxOut {
+                    value: 50,
+                    script_pubkey: Script::from(script.to_vec()),
+                }],
+            };
+            for txn in &txdata {
+                chain.mempool.remove(&txn.txid());
+            }
+            txdata.insert(0, coinbase);
+            let block = Block {
+                header: BlockHeader {
+                    version: 1,
+                    prev_blockhash: chain.tip(),
+                    merkle_root: Sha256dHash::default(),
+                    time: chain.nonce,
+                    bits: 0,
+                    nonce: chain.nonce,
+                },
+                txdata,
+            };
+            let blockhash = block.bitcoin_hash();
+            chain.active.push(blockhash);
+            chain.blocks.insert(blockhash, block);
+            blockhash
+        }
+
+        pub fn mine(&self, txdata: Vec<Transaction>) -> Sha256dHash {
+            self.mine_to(b"", txdata)
+        }
+
+        /// Adds `txn` to the mempool (until it is mined).
+        pub fn send(&self, txn: Transaction) {
+            self.chain.lock().unwrap().mempool.insert(txn.txid(), txn);
+        }
+
+        /// Disconnects the latest blocks (which can still be fetched by their hashes).
+        pub fn invalidate(&self, count: usize) {
+            let mut chain = self.chain.lock().unwrap();
+            let height = chain.active.len() - count;
+            chain.active.truncate(height);
+        }
+    }
+
+    fn serve(stream: TcpStream, chain: Arc<Mutex<Chain>>) {
+        let mut writer = stream.try_clone().unwrap();
+        let mut reader = BufReader::new(stream);
+        loop {
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    return; // disconnected
+                }
+                let line = line.trim();
+                if line.is_empty() {
+                    break;
+                }
+                let mut parts = line.splitn(2, ": ");
+                if let (Some("Content-Length"), Some(value)) = (parts.next(), parts.next()) {
+                    content_length = value.parse().unwrap();
+                }
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+            let requests: Vec<Value> = serde_json::from_slice(&body).unwrap();
+            let replies: Vec<Value> = requests
+                .iter()
+                .map(|request| {
+                    let method = request["method"].as_str().unwrap();
+                    let params = request["params"].as_array().unwrap();
+                    match chain.lock().unwrap().handle(method, params) {
+                        Some(result) => json!({"id": request["id"], "result": result}),
+                        None => json!({"id": request["id"], "error": {"code": -5, "message": "not found"}}),
+                    }
+                })
+                .collect();
+            let reply = Value::Array(replies).to_string();
+            let msg = format!(
+                "HTTP/1.1 200 OK\nContent-Length: {}\n\n{}\n",
+                reply.len() + 1,
+                reply
+            );
+            writer.write_all(msg.as_bytes()).unwrap();
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     #[test]
     fn test_fakestore() {
         use crate::fake;
-        use crate::store::{ReadStore, Row, WriteStore};
+        use crate::store::{Family, ReadStore, Row, WriteStore};
 
         let store = fake::FakeStore {};
         store.write(vec![Row {
             key: b"k".to_vec(),
             value: b"v".to_vec(),
         }]);
+        store.delete_and_write(vec![b"k".to_vec()], vec![]);
         store.flush();
         // nothing was actually written
-        assert!(store.get(b"").is_none());
-        assert!(store.scan(b"").is_empty());
+        assert!(store.get(Family::Default, b"").is_none());
+        assert!(store.scan(Family::Default, b"").next().is_none());
     }
 }
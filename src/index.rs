@@ -7,8 +7,10 @@ use bitcoin::util::hash::Sha256dHash;
 use crypto::digest::Digest;
 use crypto::sha2::Sha256;
 use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::iter::FromIterator;
-use std::sync::RwLock;
+use std::mem;
+use std::sync::{Arc, RwLock};
 
 use crate::daemon::Daemon;
 use crate::errors::*;
@@ -16,7 +18,7 @@ use crate::metrics::{
     Counter, Gauge, HistogramOpts, HistogramTimer, HistogramVec, MetricOpts, Metrics,
 };
 use crate::signal::Waiter;
-use crate::store::{ReadStore, Row, WriteStore};
+use crate::store::{Family, ReadStore, Row, WriteStore};
 use crate::util::{
     full_hash, hash_prefix, spawn_thread, Bytes, FullHash, HashPrefix, HeaderEntry, HeaderList,
     HeaderMap, SyncChannel, HASH_PREFIX_LEN,
@@ -156,6 +158,126 @@ impl TxRow {
     }
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct UtxoKey {
+    code: u8,
+    script_hash: FullHash,
+    pub txid: FullHash,
+    pub output_index: u32,
+}
+
+/// An unspent output of a confirmed transaction (the row is deleted when the output is spent).
+pub struct UtxoRow {
+    pub key: UtxoKey,
+    pub height: u32, // value
+    pub value: u64,  // value
+}
+
+impl UtxoRow {
+    pub fn new(txid: &Sha256dHash, output_index: usize, output: &TxOut, height: u32) -> UtxoRow {
+        UtxoRow {
+            key: UtxoKey {
+                code: b'U',
+                script_hash: compute_script_hash(&output.script_pubkey[..]),
+                txid: full_hash(&txid[..]),
+                output_index: output_index as u32,
+            },
+            height,
+            value: output.value,
+        }
+    }
+
+    pub fn filter(script_hash: &[u8]) -> Bytes {
+        [b"U", script_hash].concat()
+    }
+
+    pub fn to_row(&self) -> Row {
+        Row {
+            key: bincode::serialize(&self.key).unwrap(),
+            value: bincode::serialize(&(self.height, self.value)).unwrap(),
+        }
+    }
+
+    // Allows finding (and deleting) the UTXO row of a spent output, by its outpoint.
+    fn outpoint_row(&self) -> Row {
+        Row {
+            key: outpoint_key(b'P', &self.key.txid, self.key.output_index),
+            value: self.key.script_hash.to_vec(),
+        }
+    }
+
+    pub fn from_row(row: &Row) -> UtxoRow {
+        let (height, value) = bincode::deserialize(&row.value).expect("failed to parse UtxoRow");
+        UtxoRow {
+            key: bincode::deserialize(&row.key).expect("failed to parse UtxoKey"),
+            height,
+            value,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct OutPointKey {
+    code: u8,
+    txid: FullHash,
+    output_index: u32,
+}
+
+fn outpoint_key(code: u8, txid: &[u8], output_index: u32) -> Bytes {
+    bincode::serialize(&OutPointKey {
+        code,
+        txid: full_hash(txid),
+        output_index,
+    })
+    .unwrap()
+}
+
+// Marks a DB whose UTXO rows cover all of its indexed blocks.
+fn utxo_index_marker() -> Row {
+    Row {
+        key: b"u".to_vec(),
+        value: vec![],
+    }
+}
+
+/// Returns whether the DB contains an UTXO index (see `--index-utxos` flag).
+pub fn has_utxo_index(store: &ReadStore) -> bool {
+    store
+        .get(Family::Default, &utxo_index_marker().key)
+        .is_some()
+}
+
+fn raw_tx_key(txid: &Sha256dHash) -> Bytes {
+    [b"R", &txid[..]].concat()
+}
+
+// Stored only with `--store-txs` flag, so confirmed transactions are read without JSONRPC.
+fn raw_tx_row(txn: &Transaction) -> Row {
+    Row {
+        key: raw_tx_key(&txn.txid()),
+        value: serialize(txn),
+    }
+}
+
+/// Returns the confirmed transaction, if it is stored at the DB (see `--store-txs` flag).
+pub fn read_raw_tx(store: &ReadStore, txid: &Sha256dHash) -> Option<Transaction> {
+    let value = store.get(Family::RawTx, &raw_tx_key(txid))?;
+    Some(deserialize(&value).expect("failed to parse raw transaction"))
+}
+
+// Marks a DB whose raw transaction rows cover all of its indexed blocks.
+fn tx_store_marker() -> Row {
+    Row {
+        key: b"r".to_vec(),
+        value: vec![],
+    }
+}
+
+/// Returns whether the DB contains the raw transactions (see `--store-txs` flag).
+pub fn has_tx_store(store: &ReadStore) -> bool {
+    store.get(Family::Default, &tx_store_marker().key).is_some()
+}
+
 #[derive(Serialize, Deserialize)]
 struct BlockKey {
     code: u8,
@@ -186,10 +308,219 @@ pub fn index_transaction(txn: &Transaction, height: usize, rows: &mut Vec<Row>)
     rows.push(TxRow::new(&txid, height as u32).to_row());
 }
 
-pub fn index_block(block: &Block, height: usize) -> Vec<Row> {
+fn index_utxos(txn: &Transaction, height: usize, rows: &mut Vec<Row>) {
+    let txid: Sha256dHash = txn.txid();
+    for (index, output) in txn.output.iter().enumerate() {
+        let utxo = UtxoRow::new(&txid, index, output, height as u32);
+        rows.push(utxo.to_row());
+        rows.push(utxo.outpoint_row());
+    }
+}
+
+// Returns the UTXO rows (and their outpoint rows) spent by the block's inputs, to be deleted.
+// They are looked up at `unwritten` (the UTXO rows of the blocks that are not written yet)
+// before the store, and the ones found there are removed from it.
+fn spent_utxo_rows(
+    store: &ReadStore,
+    block: &Block,
+    unwritten: &mut HashMap<Bytes, Bytes>,
+) -> Vec<Row> {
+    let mut lookup = |key: Bytes| {
+        let value = unwritten
+            .remove(&key)
+            .or_else(|| store.get(Family::Utxo, &key))?;
+        Some(Row { key, value })
+    };
+    let mut rows = vec![];
+    for txn in block.txdata.iter().filter(|txn| !txn.is_coin_base()) {
+        for input in &txn.input {
+            let outpoint = &input.previous_output;
+            let outpoint_row = match lookup(outpoint_key(b'P', &outpoint.txid[..], outpoint.vout)) {
+                Some(row) => row,
+                None => {
+                    warn!("missing UTXO row of spent output {}", outpoint);
+                    continue;
+                }
+            };
+            let utxo_key = UtxoKey {
+                code: b'U',
+                script_hash: full_hash(&outpoint_row.value),
+                txid: full_hash(&outpoint.txid[..]),
+                output_index: outpoint.vout,
+            };
+            rows.extend(lookup(bincode::serialize(&utxo_key).unwrap()));
+            rows.push(outpoint_row);
+        }
+    }
+    rows
+}
+
+/// Returns the markers of the outputs spent by the block's inputs, since bulk indexing may
+/// index the inputs before the outputs (see `delete_spent_utxos`).
+pub fn spent_output_markers(block: &Block) -> Vec<Row> {
+    block
+        .txdata
+        .iter()
+        .filter(|txn| !txn.is_coin_base())
+        .flat_map(|txn| txn.input.iter())
+        .map(|input| Row {
+            key: outpoint_key(
+                b'S',
+                &input.previous_output.txid[..],
+                input.previous_output.vout,
+            ),
+            value: vec![],
+        })
+        .collect()
+}
+
+/// Deletes the UTXO rows of the outputs marked as spent (together with the markers),
+/// after bulk indexing is over.
+pub fn delete_spent_utxos<S: ReadStore + WriteStore>(store: &S) {
+    let mut keys = vec![];
+    let mut count = 0;
+    for marker in store.scan(Family::Utxo, b"S") {
+        let key: OutPointKey =
+            bincode::deserialize(&marker.key).expect("failed to parse OutPointKey");
+        let outpoint_key = outpoint_key(b'P', &key.txid, key.output_index);
+        if let Some(script_hash) = store.get(Family::Utxo, &outpoint_key) {
+            let utxo_key = UtxoKey {
+                code: b'U',
+                script_hash: full_hash(&script_hash),
+                txid: key.txid,
+                output_index: key.output_index,
+            };
+            keys.push(bincode::serialize(&utxo_key).unwrap());
+            keys.push(outpoint_key);
+            count += 1;
+        }
+        keys.push(marker.key);
+        if keys.len() >= 300_000 {
+            store.delete_and_write(mem::take(&mut keys), vec![]);
+        }
+    }
+    store.delete_and_write(keys, vec![]);
+    info!("deleted {} spent UTXO rows", count);
+}
+
+// Larger sets are not worth tracking (e.g. during initial sync).
+const MAX_TRACKED_CHANGES: usize = 1 << 20;
+
+/// Hash prefixes touched by new (or removed) transactions,
+/// so only the affected subscriptions have to be re-evaluated.
+#[derive(Default)]
+pub struct Changes {
+    script_hashes: HashSet<HashPrefix>, // of the funded outputs
+    txids: HashSet<HashPrefix>,         // of the transactions themselves, and of the spent ones
+    overflow: bool,                     // too many changes - assume everything was touched
+}
+
+impl Changes {
+    pub fn add_transaction(&mut self, txn: &Transaction) {
+        if self.overflow {
+            return;
+        }
+        self.txids.insert(hash_prefix(&txn.txid()[..]));
+        if !txn.is_coin_base() {
+            for input in &txn.input {
+                self.txids
+                    .insert(hash_prefix(&input.previous_output.txid[..]));
+            }
+        }
+        for output in &txn.output {
+            let script_hash = compute_script_hash(&output.script_pubkey[..]);
+            self.script_hashes.insert(hash_prefix(&script_hash));
+        }
+        self.check_overflow();
+    }
+
+    // Uses the keys of an orphaned block's rows (see `read_undo`).
+    fn add_orphaned_key(&mut self, key: &[u8]) {
+        if self.overflow {
+            return;
+        }
+        let row = Row {
+            key: key.to_vec(),
+            value: vec![],
+        };
+        match key.first() {
+            Some(b'I') => {
+                let prev_hash_prefix = TxInRow::from_row(&row).key.prev_hash_prefix;
+                self.txids.insert(prev_hash_prefix);
+            }
+            Some(b'O') => {
+                let script_hash_prefix = TxOutRow::from_row(&row).key.script_hash_prefix;
+                self.script_hashes.insert(script_hash_prefix);
+            }
+            Some(b'T') => {
+                let txid: TxKey = bincode::deserialize(key).expect("failed to parse TxKey");
+                self.txids.insert(hash_prefix(&txid.txid));
+            }
+            _ => (),
+        }
+        self.check_overflow();
+    }
+
+    pub fn extend(&mut self, other: Changes) {
+        self.overflow |= other.overflow;
+        if !self.overflow {
+            self.script_hashes.extend(other.script_hashes);
+            self.txids.extend(other.txids);
+        }
+        self.check_overflow();
+    }
+
+    fn check_overflow(&mut self) {
+        if self.overflow || self.script_hashes.len() + self.txids.len() > MAX_TRACKED_CHANGES {
+            self.overflow = true;
+            self.script_hashes.clear();
+            self.txids.clear();
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        !self.overflow && self.script_hashes.is_empty() && self.txids.is_empty()
+    }
+
+    /// Returns whether the status of `script_hash` (depending on `related_txids`) may have changed.
+    pub fn affects(&self, script_hash: &[u8], related_txids: &HashSet<HashPrefix>) -> bool {
+        self.overflow
+            || self.script_hashes.contains(&hash_prefix(script_hash))
+            || !self.txids.is_disjoint(related_txids)
+    }
+}
+
+impl fmt::Debug for Changes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.overflow {
+            write!(f, "Changes(all)")
+        } else {
+            write!(
+                f,
+                "Changes({} script hashes, {} txids)",
+                self.script_hashes.len(),
+                self.txids.len()
+            )
+        }
+    }
+}
+
+pub fn index_block(block: &Block, height: usize, utxos: bool, txs: bool) -> Vec<Row> {
     let mut rows = vec![];
     for txn in &block.txdata {
         index_transaction(&txn, height, &mut rows);
+        if utxos {
+            index_utxos(txn, height, &mut rows);
+        }
+        if txs {
+            rows.push(raw_tx_row(txn));
+        }
+    }
+    if utxos {
+        rows.push(utxo_index_marker()); // written together with the block's UTXO rows
+    }
+    if txs {
+        rows.push(tx_store_marker()); // written together with the block's raw transactions
     }
     let blockhash = block.bitcoin_hash();
     // Persist block hash and header
@@ -204,6 +535,76 @@ pub fn index_block(block: &Block, height: usize) -> Vec<Row> {
     rows
 }
 
+// Undo data is kept only for the latest blocks, so deeper reorgs are not supported.
+pub const MAX_REORG_DEPTH: usize = 100;
+
+#[derive(Serialize, Deserialize)]
+struct UndoKey {
+    code: u8,
+    height: u32,
+}
+
+fn undo_key(height: usize) -> Bytes {
+    bincode::serialize(&UndoKey {
+        code: b'D',
+        height: height as u32,
+    })
+    .unwrap()
+}
+
+// Returns the keys to be deleted if the block gets orphaned.
+fn block_keys(block_rows: &[Row]) -> Vec<Bytes> {
+    let markers = [utxo_index_marker().key, tx_store_marker().key]; // shared by all blocks
+    block_rows
+        .iter()
+        .map(|row| &row.key)
+        .filter(|key| !markers.contains(key))
+        .cloned()
+        .collect()
+}
+
+/// Returns the undo data of a block: the keys of its rows, to be deleted if it gets orphaned,
+/// and the UTXO rows spent by it, to be restored.
+pub fn block_undo_row(
+    blockhash: &Sha256dHash,
+    height: usize,
+    block_rows: &[Row],
+    spent_rows: &[Row],
+) -> Row {
+    let keys = block_keys(block_rows);
+    let spent: Vec<(&Bytes, &Bytes)> = spent_rows
+        .iter()
+        .map(|row| (&row.key, &row.value))
+        .collect();
+    Row {
+        key: undo_key(height),
+        value: bincode::serialize(&(full_hash(&blockhash[..]), keys, spent)).unwrap(),
+    }
+}
+
+// Returns `None` if the block has no undo data (e.g. it was indexed before the undo data was
+// introduced, or it is deeper than MAX_REORG_DEPTH).
+fn read_undo(store: &ReadStore, header: &HeaderEntry) -> Result<Option<(Vec<Bytes>, Vec<Row>)>> {
+    let value = match store.get(Family::Undo, &undo_key(header.height())) {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    let (blockhash, keys, spent): (FullHash, Vec<Bytes>, Vec<(Bytes, Bytes)>) =
+        bincode::deserialize(&value).expect("failed to parse undo data");
+    if blockhash != full_hash(&header.hash()[..]) {
+        bail!(
+            "undo data at height {} does not belong to block {}",
+            header.height(),
+            header.hash()
+        );
+    }
+    let spent = spent
+        .into_iter()
+        .map(|(key, value)| Row { key, value })
+        .collect();
+    Ok(Some((keys, spent)))
+}
+
 pub fn last_indexed_block(blockhash: &Sha256dHash) -> Row {
     // Store last indexed block (i.e. all previous blocks were indexed)
     Row {
@@ -214,25 +615,31 @@ pub fn last_indexed_block(blockhash: &Sha256dHash) -> Row {
 
 pub fn read_indexed_blockhashes(store: &ReadStore) -> HashSet<Sha256dHash> {
     let mut result = HashSet::new();
-    for row in store.scan(b"B") {
+    for row in store.scan(Family::Block, b"B") {
         let key: BlockKey = bincode::deserialize(&row.key).unwrap();
         result.insert(deserialize(&key.hash).unwrap());
     }
     result
 }
 
-fn read_indexed_headers(store: &ReadStore) -> HeaderList {
-    let latest_blockhash: Sha256dHash = match store.get(b"L") {
-        // latest blockheader persisted in the DB.
-        Some(row) => deserialize(&row).unwrap(),
-        None => Sha256dHash::default(),
-    };
+/// Returns the headers of all the indexed blocks (including the ones not connected to the `L` row).
+pub fn read_block_headers(store: &ReadStore) -> HeaderMap {
     let mut map = HeaderMap::new();
-    for row in store.scan(b"B") {
+    for row in store.scan(Family::Block, b"B") {
         let key: BlockKey = bincode::deserialize(&row.key).unwrap();
         let header: BlockHeader = deserialize(&row.value).unwrap();
         map.insert(deserialize(&key.hash).unwrap(), header);
     }
+    map
+}
+
+fn read_indexed_headers(store: &ReadStore) -> HeaderList {
+    let latest_blockhash: Sha256dHash = match store.get(Family::Default, b"L") {
+        // latest blockheader persisted in the DB.
+        Some(row) => deserialize(&row).unwrap(),
+        None => Sha256dHash::default(),
+    };
+    let mut map = read_block_headers(store);
     let mut headers = vec![];
     let null_hash = Sha256dHash::default();
     let mut blockhash = latest_blockhash;
@@ -304,11 +711,12 @@ impl Stats {
 }
 
 pub struct Index {
-    // TODO: store also latest snapshot.
-    headers: RwLock<HeaderList>,
+    headers: RwLock<Arc<HeaderList>>, // replaced (instead of modified) by each update
     daemon: Daemon,
     stats: Stats,
     batch_size: usize,
+    utxos: bool,
+    txs: bool,
 }
 
 impl Index {
@@ -317,21 +725,54 @@ impl Index {
         daemon: &Daemon,
         metrics: &Metrics,
         batch_size: usize,
+        utxos: bool,
+        txs: bool,
     ) -> Result<Index> {
+        let has_utxos = has_utxo_index(store);
+        if has_utxos && !utxos {
+            bail!("DB contains an UTXO index, which requires --index-utxos to be kept up-to-date");
+        }
+        if !has_utxos && utxos && !read_indexed_blockhashes(store).is_empty() {
+            bail!("DB was indexed without --index-utxos, so it has to be re-indexed from scratch");
+        }
+        let has_txs = has_tx_store(store);
+        if has_txs && !txs {
+            bail!("DB contains raw transactions, which require --store-txs to be kept up-to-date");
+        }
+        if !has_txs && txs && !read_indexed_blockhashes(store).is_empty() {
+            bail!("DB was indexed without --store-txs, so it has to be re-indexed from scratch");
+        }
         let stats = Stats::new(metrics);
         let headers = read_indexed_headers(store);
         stats.height.set((headers.len() as i64) - 1);
         Ok(Index {
-            headers: RwLock::new(headers),
+            headers: RwLock::new(Arc::new(headers)),
             daemon: daemon.reconnect()?,
             stats,
             batch_size,
+            utxos,
+            txs,
         })
     }
 
+    /// Returns whether the UTXO rows are maintained (see `--index-utxos` flag).
+    pub fn has_utxos(&self) -> bool {
+        self.utxos
+    }
+
+    /// Returns whether the raw transactions are stored (see `--store-txs` flag).
+    pub fn has_txs(&self) -> bool {
+        self.txs
+    }
+
     pub fn reload(&self, store: &ReadStore) {
         let mut headers = self.headers.write().unwrap();
-        *headers = read_indexed_headers(store);
+        *headers = Arc::new(read_indexed_headers(store));
+    }
+
+    /// Returns the indexed headers, which are not affected by later updates.
+    pub fn headers(&self) -> Arc<HeaderList> {
+        Arc::clone(&self.headers.read().unwrap())
     }
 
     pub fn best_header(&self) -> Option<HeaderEntry> {
@@ -347,13 +788,88 @@ impl Index {
             .cloned()
     }
 
-    pub fn update(&self, store: &WriteStore, waiter: &Waiter) -> Result<Sha256dHash> {
+    // Deletes the rows of the orphaned blocks (restoring the UTXO rows they spent), and moves
+    // the last indexed block back to their parent (atomically), returning the changes made by
+    // the deleted rows. Blocks without undo data are fetched from the daemon (which keeps the
+    // stale blocks), and their keys are recomputed - unless their spent UTXO rows are needed.
+    fn disconnect<S: ReadStore + WriteStore>(
+        &self,
+        store: &S,
+        daemon: &Daemon,
+        orphaned_headers: &[HeaderEntry],
+    ) -> Result<Changes> {
+        let mut changes = Changes::default();
+        let first = match orphaned_headers.first() {
+            Some(first) => first,
+            None => return Ok(changes),
+        };
+        warn!("reorg: disconnecting {} blocks", orphaned_headers.len());
+        let mut keys = vec![];
+        let mut restored = HashMap::<Bytes, Bytes>::new();
+        // the latest block is disconnected first, so the outputs created by the orphaned blocks
+        // are not restored
+        for header in orphaned_headers.iter().rev() {
+            let (block_keys, spent_rows) = match read_undo(store, header)? {
+                Some(undo) => undo,
+                None if self.utxos => bail!(
+                    "missing undo data for block {}, so its spent UTXO rows can't be restored",
+                    header.hash()
+                ),
+                None => {
+                    warn!(
+                        "missing undo data for block {}, re-indexing it",
+                        header.hash()
+                    );
+                    let block = daemon.getblock(header.hash())?;
+                    let rows = index_block(&block, header.height(), self.utxos, self.txs);
+                    (block_keys(&rows), vec![])
+                }
+            };
+            restored.extend(spent_rows.into_iter().map(Row::into_pair));
+            for key in block_keys {
+                changes.add_orphaned_key(&key);
+                restored.remove(&key); // e.g. an output spent by the same block
+                keys.push(key);
+            }
+            keys.push(undo_key(header.height()));
+        }
+        let mut rows: Vec<Row> = restored
+            .into_iter()
+            .map(|(key, value)| Row { key, value })
+            .collect();
+        rows.push(last_indexed_block(&first.header().prev_blockhash)); // the fork point
+        store.delete_and_write(keys, rows);
+        let mut headers = HeaderList::clone(&self.headers());
+        headers.truncate(first.height());
+        *self.headers.write().unwrap() = Arc::new(headers);
+        Ok(changes)
+    }
+
+    /// Indexes new blocks (after disconnecting the orphaned ones on reorg),
+    /// returning the new tip and the changes made by their transactions.
+    pub fn update<S: ReadStore + WriteStore>(
+        &self,
+        store: &S,
+        waiter: &Waiter,
+    ) -> Result<(Sha256dHash, Changes)> {
         let daemon = self.daemon.reconnect()?;
         let tip = daemon.getbestblockhash()?;
-        let new_headers: Vec<HeaderEntry> = {
+        let (new_headers, orphaned_headers) = {
             let indexed_headers = self.headers.read().unwrap();
-            indexed_headers.order(daemon.get_new_headers(&indexed_headers, &tip)?)
+            let new_headers =
+                indexed_headers.order(daemon.get_new_headers(&indexed_headers, &tip)?);
+            // on reorg, the new headers replace the indexed ones (starting at the fork height)
+            let orphaned_headers: Vec<HeaderEntry> = match new_headers.first() {
+                Some(first) => indexed_headers
+                    .iter()
+                    .skip(first.height())
+                    .cloned()
+                    .collect(),
+                None => vec![],
+            };
+            (new_headers, orphaned_headers)
         };
+        let mut changes = self.disconnect(store, &daemon, &orphaned_headers)?;
         new_headers.last().map(|tip| {
             info!("{:?} ({} left to index)", tip, new_headers.len());
         });
@@ -388,6 +904,9 @@ impl Index {
             }
 
             let mut rows = vec![];
+            let mut deleted_keys = vec![];
+            // the UTXO rows of this batch, which may be spent before they are written
+            let mut unwritten = HashMap::<Bytes, Bytes>::new();
             for block in &batch {
                 let blockhash = block.bitcoin_hash();
                 let height = *height_map
@@ -395,14 +914,34 @@ impl Index {
                     .expect(&format!("missing header for block {}", blockhash));
 
                 let timer = self.stats.start_timer("index");
-                let mut block_rows = index_block(block, height);
+                let mut block_rows = index_block(block, height, self.utxos, self.txs);
+                let mut spent_rows = vec![];
+                if self.utxos {
+                    unwritten.extend(
+                        block_rows
+                            .iter()
+                            .filter(|row| row.family() == Family::Utxo)
+                            .map(|row| (row.key.clone(), row.value.clone())),
+                    );
+                    spent_rows = spent_utxo_rows(store, block, &mut unwritten);
+                    deleted_keys.extend(spent_rows.iter().map(|row| row.key.clone()));
+                }
+                block_rows.push(block_undo_row(&blockhash, height, &block_rows, &spent_rows));
                 block_rows.push(last_indexed_block(&blockhash));
+                if height >= MAX_REORG_DEPTH {
+                    deleted_keys.push(undo_key(height - MAX_REORG_DEPTH));
+                }
                 rows.extend(block_rows);
                 timer.observe_duration();
                 self.stats.update(block, height);
+                for txn in &block.txdata {
+                    changes.add_transaction(txn);
+                }
             }
+            // the UTXO rows spent by this batch are not written
+            rows.retain(|row| row.family() != Family::Utxo || unwritten.contains_key(&row.key));
             let timer = self.stats.start_timer("write");
-            store.write(rows);
+            store.delete_and_write(deleted_keys, rows);
             timer.observe_duration();
         }
         let timer = self.stats.start_timer("flush");
@@ -410,8 +949,10 @@ impl Index {
         timer.observe_duration();
 
         fetcher.join().expect("block fetcher failed");
-        self.headers.write().unwrap().apply(new_headers);
-        assert_eq!(tip, *self.headers.read().unwrap().tip());
-        Ok(tip)
+        let mut headers = HeaderList::clone(&self.headers());
+        headers.apply(new_headers);
+        assert_eq!(tip, *headers.tip());
+        *self.headers.write().unwrap() = Arc::new(headers);
+        Ok((tip, changes))
     }
 }
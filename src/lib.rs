@@ -17,6 +17,7 @@ extern crate serde_json;
 
 pub mod app;
 pub mod bulk;
+pub mod check;
 pub mod config;
 pub mod daemon;
 pub mod errors;
@@ -24,8 +25,12 @@ pub mod fake;
 pub mod index;
 pub mod mempool;
 pub mod metrics;
+pub mod peers;
 pub mod query;
 pub mod rpc;
 pub mod signal;
 pub mod store;
+pub mod tls;
+pub mod transport;
 pub mod util;
+pub mod websocket;
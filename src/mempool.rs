@@ -8,11 +8,11 @@ use std::sync::Mutex;
 
 use crate::daemon::{Daemon, MempoolEntry};
 use crate::errors::*;
-use crate::index::index_transaction;
+use crate::index::{index_transaction, Changes};
 use crate::metrics::{
     Gauge, GaugeVec, HistogramOpts, HistogramTimer, HistogramVec, MetricOpts, Metrics,
 };
-use crate::store::{ReadStore, Row};
+use crate::store::{Family, ReadStore, Row, RowIterator};
 use crate::util::Bytes;
 
 const VSIZE_BIN_WIDTH: u32 = 100_000; // in vbytes
@@ -68,26 +68,28 @@ impl MempoolStore {
 }
 
 impl ReadStore for MempoolStore {
-    fn get(&self, key: &[u8]) -> Option<Bytes> {
+    // all the families are kept together, since their keys have distinct codes
+    fn get(&self, _family: Family, key: &[u8]) -> Option<Bytes> {
         Some(self.map.get(key)?.last()?.to_vec())
     }
-    fn scan(&self, prefix: &[u8]) -> Vec<Row> {
+    fn scan<'a>(&'a self, family: Family, prefix: &[u8]) -> RowIterator<'a> {
+        self.scan_from(family, prefix, prefix)
+    }
+    fn scan_from<'a>(&'a self, _family: Family, prefix: &[u8], start: &[u8]) -> RowIterator<'a> {
+        let prefix = prefix.to_vec();
         let range = self
             .map
-            .range((Bound::Included(prefix.to_vec()), Bound::Unbounded));
-        let mut rows = vec![];
-        for (key, values) in range {
-            if !key.starts_with(prefix) {
-                break;
-            }
-            if let Some(value) = values.last() {
-                rows.push(Row {
-                    key: key.to_vec(),
-                    value: value.to_vec(),
-                });
-            }
-        }
-        rows
+            .range((Bound::Included(start.to_vec()), Bound::Unbounded));
+        Box::new(
+            range
+                .take_while(move |(key, _)| key.starts_with(&prefix))
+                .filter_map(|(key, values)| {
+                    Some(Row {
+                        key: key.to_vec(),
+                        value: values.last()?.to_vec(),
+                    })
+                }),
+        )
     }
 }
 
@@ -176,6 +178,28 @@ impl Tracker {
         self.items.get(txid).map(|stats| stats.tx.clone())
     }
 
+    pub fn get_fee(&self, txid: &Sha256dHash) -> Option<u64> {
+        self.items.get(txid).map(|stats| stats.entry.fee())
+    }
+
+    /// Returns the mempool transactions spent by the given mempool transaction.
+    pub fn unconfirmed_parents(&self, txid: &Sha256dHash) -> Vec<Sha256dHash> {
+        let item = match self.items.get(txid) {
+            Some(item) => item,
+            None => return vec![],
+        };
+        let mut parents: Vec<Sha256dHash> = item
+            .tx
+            .input
+            .iter()
+            .map(|input| input.previous_output.txid)
+            .filter(|parent| self.items.contains_key(parent))
+            .collect();
+        parents.sort_unstable();
+        parents.dedup();
+        parents
+    }
+
     /// Returns vector of (fee_rate, vsize) pairs, where fee_{n-1} > fee_n and vsize_n is the
     /// total virtual size of mempool transactions with fee in the bin [fee_{n-1}, fee_n].
     /// Note: fee_{-1} is implied to be infinite.
@@ -187,7 +211,8 @@ impl Tracker {
         &self.index
     }
 
-    pub fn update(&mut self, daemon: &Daemon) -> Result<()> {
+    /// Syncs with the daemon's mempool, returning the changes made by added and removed transactions.
+    pub fn update(&mut self, daemon: &Daemon) -> Result<Changes> {
         let timer = self.stats.start_timer("fetch");
         let new_txids = daemon
             .getmempooltxids()
@@ -208,26 +233,28 @@ impl Tracker {
                 }
             })
             .collect();
+        let mut changes = Changes::default();
         if entries.is_empty() {
-            return Ok(());
+            return Ok(changes);
         }
         let txids: Vec<&Sha256dHash> = entries.iter().map(|(txid, _)| *txid).collect();
         let txs = match daemon.gettransactions(&txids) {
             Ok(txs) => txs,
             Err(err) => {
                 warn!("failed to get transactions {:?}: {}", txids, err); // e.g. new block or RBF
-                return Ok(()); // keep the mempool until next update()
+                return Ok(changes); // keep the mempool until next update()
             }
         };
         for ((txid, entry), tx) in entries.into_iter().zip(txs.into_iter()) {
             assert_eq!(tx.txid(), *txid);
+            changes.add_transaction(&tx);
             self.add(txid, tx, entry);
         }
         timer.observe_duration();
 
         let timer = self.stats.start_timer("remove");
         for txid in old_txids.difference(&new_txids) {
-            self.remove(txid);
+            changes.add_transaction(&self.remove(txid));
         }
         timer.observe_duration();
 
@@ -236,7 +263,7 @@ impl Tracker {
         timer.observe_duration();
 
         self.stats.count.set(self.items.len() as i64);
-        Ok(())
+        Ok(changes)
     }
 
     fn add(&mut self, txid: &Sha256dHash, tx: Transaction, entry: MempoolEntry) {
@@ -244,12 +271,13 @@ impl Tracker {
         self.items.insert(*txid, Item { tx, entry });
     }
 
-    fn remove(&mut self, txid: &Sha256dHash) {
+    fn remove(&mut self, txid: &Sha256dHash) -> Transaction {
         let stats = self
             .items
             .remove(txid)
             .expect(&format!("missing mempool tx {}", txid));
         self.index.remove(&stats.tx);
+        stats.tx
     }
 
     fn update_fee_histogram(&mut self) {
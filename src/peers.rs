@@ -0,0 +1,511 @@
+use error_chain::ChainedError;
+use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+use serde_json::{self, from_str, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::errors::*;
+use crate::util::{spawn_thread, Channel};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(10 * 60);
+const TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_FAILURES: usize = 3; // forget peers that keep failing the check
+const MAX_KNOWN_PEERS: usize = 1000; // announcements are unauthenticated
+const MAX_ANNOUNCED_HOSTS: usize = 4; // e.g. a clearnet and an onion host, per announcement
+const MAX_PENDING_ANNOUNCEMENTS: usize = 100; // announcements are resolved by the peers thread
+
+/// An Electrum server's host name and ports, as announced via `server.add_peer`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PeerAddr {
+    pub host: String,
+    pub tcp_port: Option<u16>,
+    pub ssl_port: Option<u16>,
+}
+
+fn port_from_value(ports: &Value, name: &str) -> Option<u16> {
+    ports.get(name)?.as_u64().map(|port| port as u16)
+}
+
+fn addrs_from_features(features: &Value) -> Result<Vec<PeerAddr>> {
+    let hosts = features
+        .get("hosts")
+        .and_then(Value::as_object)
+        .chain_err(|| "missing hosts")?;
+    if hosts.len() > MAX_ANNOUNCED_HOSTS {
+        bail!(ErrorKind::BadRequest(format!(
+            "too many hosts: {} (limit: {})",
+            hosts.len(),
+            MAX_ANNOUNCED_HOSTS
+        )));
+    }
+    Ok(hosts
+        .iter()
+        .map(|(host, ports)| PeerAddr {
+            host: host.clone(),
+            tcp_port: port_from_value(ports, "tcp_port"),
+            ssl_port: port_from_value(ports, "ssl_port"),
+        })
+        .collect())
+}
+
+fn resolve(host: &str) -> Vec<IpAddr> {
+    (host, 0)
+        .to_socket_addrs()
+        .map(|addrs| addrs.map(|addr| addr.ip()).collect())
+        .unwrap_or_default()
+}
+
+/// Minimal line-based JSON-RPC client, for querying other Electrum servers.
+struct Client<S: Read + Write> {
+    stream: BufReader<S>,
+    id: usize,
+}
+
+impl<S: Read + Write> Client<S> {
+    fn new(stream: S) -> Client<S> {
+        Client {
+            stream: BufReader::new(stream),
+            id: 0,
+        }
+    }
+
+    fn call(&mut self, method: &str, params: Value) -> Result<Value> {
+        self.id += 1;
+        let request = json!({"jsonrpc": "2.0", "id": self.id, "method": method, "params": params});
+        self.stream
+            .get_mut()
+            .write_all((request.to_string() + "\n").as_bytes())
+            .chain_err(|| format!("failed to send {}", method))?;
+        let mut line = String::new();
+        if self
+            .stream
+            .read_line(&mut line)
+            .chain_err(|| format!("failed to receive {} reply", method))?
+            == 0
+        {
+            bail!("disconnected before {} reply", method);
+        }
+        let mut reply: Value = from_str(&line).chain_err(|| "invalid JSON reply")?;
+        match reply.get("error") {
+            None | Some(Value::Null) => (),
+            Some(err) => bail!("{} failed: {}", method, err),
+        }
+        Ok(reply
+            .get_mut("result")
+            .map(Value::take)
+            .unwrap_or(Value::Null))
+    }
+}
+
+struct VerifiedPeer {
+    ip: IpAddr,
+    addr: PeerAddr,
+    features: Value,
+}
+
+struct Announcement {
+    addrs: Vec<PeerAddr>,
+    source: IpAddr,
+}
+
+struct State {
+    known: Vec<PeerAddr>, // persisted
+    pending: Vec<Announcement>,
+    failures: HashMap<String, usize>,
+    verified: BTreeMap<String, VerifiedPeer>, // by host
+}
+
+impl State {
+    fn insert(&mut self, addr: PeerAddr) -> bool {
+        if let Some(known) = self.known.iter_mut().find(|known| known.host == addr.host) {
+            if *known == addr {
+                return false;
+            }
+            *known = addr; // e.g. the peer has changed its ports
+            return true;
+        }
+        if self.known.len() >= MAX_KNOWN_PEERS {
+            return false;
+        }
+        self.known.push(addr);
+        true
+    }
+}
+
+/// Keeps track of other Electrum servers, for `server.peers.subscribe`.
+///
+/// Known peers are persisted, and periodically checked by connecting them and
+/// making sure they serve the same chain (using their `server.features` reply).
+pub struct Peers {
+    path: PathBuf,
+    features: Value, // of this server
+    state: Mutex<State>,
+    wakeup: Mutex<Sender<()>>,
+}
+
+fn load(path: &Path) -> Result<Vec<PeerAddr>> {
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    let data = fs::read(path).chain_err(|| format!("failed to read {:?}", path))?;
+    serde_json::from_slice(&data).chain_err(|| format!("failed to parse {:?}", path))
+}
+
+impl Peers {
+    fn new(path: &Path, seeds: Vec<PeerAddr>, features: Value, wakeup: Sender<()>) -> Peers {
+        let known = load(path).unwrap_or_else(|e| {
+            warn!("ignoring known peers: {}", e.display_chain());
+            vec![]
+        });
+        let mut state = State {
+            known,
+            pending: vec![],
+            failures: HashMap::new(),
+            verified: BTreeMap::new(),
+        };
+        for seed in seeds {
+            state.insert(seed);
+        }
+        debug!("{} known peers", state.known.len());
+        Peers {
+            path: path.to_path_buf(),
+            features,
+            state: Mutex::new(state),
+            wakeup: Mutex::new(wakeup),
+        }
+    }
+
+    /// Loads the known peers from `path`, and starts checking them in the background.
+    pub fn start(path: &Path, seeds: Vec<PeerAddr>, features: Value) -> Arc<Peers> {
+        let chan = Channel::new();
+        let peers = Arc::new(Peers::new(path, seeds, features, chan.sender()));
+        let checker = peers.clone();
+        spawn_thread("peers", move || loop {
+            checker.check_peers();
+            let deadline = Instant::now() + CHECK_INTERVAL;
+            loop {
+                let now = Instant::now();
+                if now >= deadline {
+                    break;
+                }
+                let woken = chan.receiver().recv_timeout(deadline - now).is_ok();
+                if woken && checker.accept_announcements() {
+                    break; // check the new peers
+                }
+            }
+        });
+        peers
+    }
+
+    fn save(&self, state: &State) {
+        let tmp_path = self.path.with_extension("tmp");
+        let result = serde_json::to_vec_pretty(&state.known)
+            .chain_err(|| "failed to serialize peers")
+            .and_then(|data| fs::write(&tmp_path, data).chain_err(|| "failed to write peers"))
+            .and_then(|()| fs::rename(&tmp_path, &self.path).chain_err(|| "failed to rename"));
+        if let Err(e) = result {
+            warn!("failed to save peers to {:?}: {}", self.path, e);
+        }
+    }
+
+    fn same_chain(&self, features: &Value) -> bool {
+        match (
+            features.get("genesis_hash"),
+            self.features.get("genesis_hash"),
+        ) {
+            (Some(theirs), Some(ours)) => theirs == ours,
+            _ => false,
+        }
+    }
+
+    /// Handles a `server.add_peer` announcement, received from `source`.
+    ///
+    /// The announcement is only queued here, since resolving its hosts may block:
+    /// the peers thread accepts only its hosts that resolve to `source`.
+    pub fn add(&self, features: &Value, source: IpAddr) -> Result<bool> {
+        if !self.same_chain(features) {
+            debug!("ignoring peer on another chain: {}", features);
+            return Ok(false);
+        }
+        let addrs: Vec<PeerAddr> = addrs_from_features(features)?
+            .into_iter()
+            .filter(|addr| !addr.host.ends_with(".onion")) // not reachable without Tor
+            .collect();
+        if addrs.is_empty() {
+            return Ok(false);
+        }
+        let mut state = self.state.lock().unwrap();
+        if state.pending.len() >= MAX_PENDING_ANNOUNCEMENTS {
+            debug!(
+                "ignoring peer (too many pending announcements): {}",
+                features
+            );
+            return Ok(false);
+        }
+        state.pending.push(Announcement { addrs, source });
+        let _ = self.wakeup.lock().unwrap().send(());
+        Ok(true)
+    }
+
+    /// Resolves the pending announcements, returning whether new peers were accepted.
+    fn accept_announcements(&self) -> bool {
+        let pending: Vec<Announcement> = self.state.lock().unwrap().pending.drain(..).collect();
+        let mut accepted = false;
+        for announcement in pending {
+            let source = announcement.source;
+            // don't let clients make us connect arbitrary hosts
+            let (addrs, ignored): (Vec<PeerAddr>, Vec<PeerAddr>) = announcement
+                .addrs
+                .into_iter()
+                .partition(|addr| resolve(&addr.host).contains(&source));
+            if !ignored.is_empty() {
+                debug!(
+                    "ignoring hosts not announced by themselves ({}): {:?}",
+                    source, ignored
+                );
+            }
+            let mut state = self.state.lock().unwrap();
+            let mut changed = false;
+            for addr in addrs {
+                changed |= state.insert(addr);
+            }
+            if changed {
+                self.save(&state);
+                accepted = true;
+            }
+        }
+        accepted
+    }
+
+    /// Returns the verified peers, in `server.peers.subscribe` format.
+    pub fn get_peers(&self) -> Value {
+        let state = self.state.lock().unwrap();
+        Value::Array(
+            state
+                .verified
+                .values()
+                .map(|peer| {
+                    let mut features = vec![];
+                    if let Some(version) = peer.features.get("protocol_max").and_then(Value::as_str)
+                    {
+                        features.push(format!("v{}", version));
+                    }
+                    if let Some(pruning) = peer.features.get("pruning").and_then(Value::as_u64) {
+                        features.push(format!("p{}", pruning));
+                    }
+                    if let Some(port) = peer.addr.tcp_port {
+                        features.push(format!("t{}", port));
+                    }
+                    if let Some(port) = peer.addr.ssl_port {
+                        features.push(format!("s{}", port));
+                    }
+                    json!([peer.ip.to_string(), peer.addr.host, features])
+                })
+                .collect(),
+        )
+    }
+
+    fn query_features<S: Read + Write>(&self, stream: S) -> Result<Value> {
+        let mut client = Client::new(stream);
+        let ours = &self.features;
+        client.call(
+            "server.version",
+            json!([
+                ours["server_version"],
+                [ours["protocol_min"], ours["protocol_max"]]
+            ]),
+        )?;
+        let features = client.call("server.features", json!([]))?;
+        if !self.same_chain(&features) {
+            bail!("different genesis hash: {}", features["genesis_hash"]);
+        }
+        let announce = match ours["hosts"].as_object() {
+            Some(hosts) => !hosts.is_empty(),
+            None => false,
+        };
+        if announce {
+            if let Err(e) = client.call("server.add_peer", json!([ours])) {
+                debug!("failed to announce to peer: {}", e);
+            }
+        }
+        Ok(features)
+    }
+
+    fn check(&self, addr: &PeerAddr) -> Result<VerifiedPeer> {
+        let (port, ssl) = match (addr.tcp_port, addr.ssl_port) {
+            (Some(port), _) => (port, false),
+            (None, Some(port)) => (port, true),
+            (None, None) => bail!("no ports"),
+        };
+        let sockaddr = (addr.host.as_str(), port)
+            .to_socket_addrs()
+            .chain_err(|| "failed to resolve")?
+            .next()
+            .chain_err(|| "no addresses")?;
+        let stream = TcpStream::connect_timeout(&sockaddr, TIMEOUT)
+            .chain_err(|| format!("failed to connect {}", sockaddr))?;
+        stream
+            .set_read_timeout(Some(TIMEOUT))
+            .and_then(|()| stream.set_write_timeout(Some(TIMEOUT)))
+            .chain_err(|| "failed to set timeouts")?;
+        let features = if ssl {
+            let mut builder = SslConnector::builder(SslMethod::tls())
+                .chain_err(|| "failed to create TLS connector")?;
+            builder.set_verify(SslVerifyMode::NONE); // self-signed certificates are common among Electrum servers
+            let stream = builder
+                .build()
+                .connect(&addr.host, stream)
+                .chain_err(|| "TLS handshake failed")?;
+            self.query_features(stream)?
+        } else {
+            self.query_features(stream)?
+        };
+        Ok(VerifiedPeer {
+            ip: sockaddr.ip(),
+            addr: addr.clone(),
+            features,
+        })
+    }
+
+    fn check_peers(&self) {
+        let known = self.state.lock().unwrap().known.clone();
+        for addr in known {
+            let result = self.check(&addr);
+            let mut state = self.state.lock().unwrap();
+            match result {
+                Ok(peer) => {
+                    debug!("verified peer {} at {}", addr.host, peer.ip);
+                    state.failures.remove(&addr.host);
+                    state.verified.insert(addr.host, peer);
+                }
+                Err(e) => {
+                    debug!("failed to verify peer {}: {}", addr.host, e);
+                    state.verified.remove(&addr.host);
+                    let failures = {
+                        let failures = state.failures.entry(addr.host.clone()).or_insert(0);
+                        *failures += 1;
+                        *failures
+                    };
+                    if failures >= MAX_FAILURES {
+                        info!("forgetting peer {} after {} failures", addr.host, failures);
+                        state.failures.remove(&addr.host);
+                        state.known.retain(|known| known.host != addr.host);
+                        self.save(&state);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::process;
+
+    const GENESIS: &str = "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f";
+
+    fn features(genesis_hash: &str, host: &str, port: u16) -> Value {
+        json!({
+            "genesis_hash": genesis_hash,
+            "hosts": {host: {"tcp_port": port, "ssl_port": null}},
+            "protocol_min": "1.4",
+            "protocol_max": "1.4.2",
+            "server_version": "stub",
+            "hash_function": "sha256",
+            "pruning": null,
+        })
+    }
+
+    // Serves a single connection, replying to server.version and server.features.
+    fn start_stub_server(genesis_hash: &'static str) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        spawn_thread("stub", move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            for line in BufReader::new(stream).lines() {
+                let request: Value = from_str(&line.unwrap()).unwrap();
+                let result = match request["method"].as_str().unwrap() {
+                    "server.version" => json!(["stub", "1.4.2"]),
+                    "server.features" => features(genesis_hash, "127.0.0.1", port),
+                    method => panic!("unexpected method {}", method),
+                };
+                let reply = json!({"jsonrpc": "2.0", "id": request["id"], "result": result});
+                writer
+                    .write_all((reply.to_string() + "\n").as_bytes())
+                    .unwrap();
+            }
+        });
+        port
+    }
+
+    #[test]
+    fn test_add_and_check_peers() {
+        let path = std::env::temp_dir().join(format!("electrs-peers-{}.json", process::id()));
+        let _ = fs::remove_file(&path);
+        let local = "127.0.0.1".parse().unwrap();
+        let (tx, _rx) = std::sync::mpsc::channel();
+        let ours = json!({"genesis_hash": GENESIS, "hosts": {}});
+        let peers = Peers::new(&path, vec![], ours.clone(), tx.clone());
+
+        let good_port = start_stub_server(GENESIS);
+        let bad_port = start_stub_server("00");
+        let bad_features = features("00", "127.0.0.1", bad_port);
+        assert!(!peers.add(&bad_features, local).unwrap()); // another chain
+        let good_features = features(GENESIS, "127.0.0.1", good_port);
+        let remote = "10.0.0.1".parse().unwrap();
+        assert!(peers.add(&good_features, remote).unwrap()); // not the announcer
+        let mut mixed_hosts = good_features.clone();
+        mixed_hosts["hosts"]["10.0.0.1"] = json!({"tcp_port": good_port}); // not a self host
+        assert!(peers.add(&mixed_hosts, local).unwrap());
+        let mut many_hosts = good_features.clone();
+        for i in 0..MAX_ANNOUNCED_HOSTS {
+            many_hosts["hosts"][format!("host{}", i)] = json!({"tcp_port": good_port});
+        }
+        assert!(peers.add(&many_hosts, local).is_err());
+        assert_eq!(peers.state.lock().unwrap().pending.len(), 2);
+        assert!(peers.state.lock().unwrap().known.is_empty()); // not resolved yet
+        assert!(peers.accept_announcements());
+
+        // make sure a peer that switched chains fails the check
+        let mut state = peers.state.lock().unwrap();
+        state.insert(PeerAddr {
+            host: "localhost".to_owned(),
+            tcp_port: Some(bad_port),
+            ssl_port: None,
+        });
+        drop(state);
+
+        peers.check_peers();
+        assert_eq!(
+            peers.get_peers(),
+            json!([[
+                "127.0.0.1",
+                "127.0.0.1",
+                ["v1.4.2", format!("t{}", good_port)]
+            ]])
+        );
+
+        // only the self host of the accepted announcement was saved
+        // (the foreign host wasn't, nor the peer inserted directly above)
+        let reloaded = Peers::new(&path, vec![], ours, tx);
+        let known = reloaded.state.lock().unwrap().known.clone();
+        assert_eq!(
+            known,
+            vec![PeerAddr {
+                host: "127.0.0.1".to_owned(),
+                tcp_port: Some(good_port),
+                ssl_port: None,
+            }]
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,20 +1,22 @@
 use bitcoin::blockdata::transaction::Transaction;
-use bitcoin::consensus::encode::deserialize;
+use bitcoin::consensus::encode::{deserialize, serialize};
 use bitcoin::util::hash::Sha256dHash;
 use crypto::digest::Digest;
 use crypto::sha2::Sha256;
+use hex;
 use lru::LruCache;
 use serde_json::Value;
-use std::collections::HashMap;
+use std::cmp;
+use std::collections::{HashMap, HashSet};
 use std::sync::{Arc, Mutex, RwLock};
 
-use crate::app::App;
+use crate::app::{App, Snapshot};
 use crate::errors::*;
-use crate::index::{compute_script_hash, TxInRow, TxOutRow, TxRow};
+use crate::index::{compute_script_hash, read_raw_tx, Changes, TxInRow, TxOutRow, TxRow, UtxoRow};
 use crate::mempool::Tracker;
 use crate::metrics::Metrics;
-use crate::store::{ReadStore, Row};
-use crate::util::{FullHash, HashPrefix, HeaderEntry};
+use crate::store::{Family, ReadStore, Row};
+use crate::util::{hash_prefix, Bytes, FullHash, HashPrefix, HeaderEntry};
 
 pub struct FundingOutput {
     pub txn_id: Sha256dHash,
@@ -32,9 +34,15 @@ struct SpendingInput {
     value: u64,
 }
 
+struct MempoolTxn {
+    fee: u64, // in satoshis
+    unconfirmed_parents: Vec<Sha256dHash>,
+}
+
 pub struct Status {
     confirmed: (Vec<FundingOutput>, Vec<SpendingInput>),
     mempool: (Vec<FundingOutput>, Vec<SpendingInput>),
+    mempool_txns: HashMap<Sha256dHash, MempoolTxn>,
 }
 
 fn calc_balance((funding, spending): &(Vec<FundingOutput>, Vec<SpendingInput>)) -> i64 {
@@ -44,6 +52,34 @@ fn calc_balance((funding, spending): &(Vec<FundingOutput>, Vec<SpendingInput>))
 }
 
 impl Status {
+    /// Prefixes of the transactions this status depends on: the funding transactions
+    /// (which may be spent) and the unconfirmed parents of its mempool transactions (which may
+    /// be confirmed).
+    pub fn related_txids(&self) -> HashSet<HashPrefix> {
+        let parents = self
+            .mempool_txns
+            .values()
+            .flat_map(|txn| txn.unconfirmed_parents.iter());
+        self.funding()
+            .map(|f| &f.txn_id)
+            .chain(parents)
+            .map(|txid| hash_prefix(&txid[..]))
+            .collect()
+    }
+
+    // Mempool transactions spending other mempool transactions are reported at height -1.
+    fn height(&self, txn_id: &Sha256dHash, height: u32) -> i32 {
+        match self.mempool_txns.get(txn_id) {
+            Some(txn) if !txn.unconfirmed_parents.is_empty() => -1,
+            _ => height as i32,
+        }
+    }
+
+    /// Returns the fee paid by a mempool transaction (in satoshis).
+    pub fn fee(&self, txn_id: &Sha256dHash) -> Option<u64> {
+        self.mempool_txns.get(txn_id).map(|txn| txn.fee)
+    }
+
     fn funding(&self) -> impl Iterator<Item = &FundingOutput> {
         self.confirmed.0.iter().chain(self.mempool.0.iter())
     }
@@ -60,17 +96,20 @@ impl Status {
         calc_balance(&self.mempool)
     }
 
+    /// Returns the confirmed transactions (ordered by height), followed by the mempool
+    /// transactions: first the ones at height 0, then the ones at height -1.
     pub fn history(&self) -> Vec<(i32, Sha256dHash)> {
         let mut txns_map = HashMap::<Sha256dHash, i32>::new();
         for f in self.funding() {
-            txns_map.insert(f.txn_id, f.height as i32);
+            txns_map.insert(f.txn_id, self.height(&f.txn_id, f.height));
         }
         for s in self.spending() {
-            txns_map.insert(s.txn_id, s.height as i32);
+            txns_map.insert(s.txn_id, self.height(&s.txn_id, s.height));
         }
         let mut txns: Vec<(i32, Sha256dHash)> =
             txns_map.into_iter().map(|item| (item.1, item.0)).collect();
-        txns.sort_unstable();
+        // mempool heights (0 and -1) are sorted by their absolute value, after the confirmed ones
+        txns.sort_unstable_by_key(|(height, txn_id)| (*height <= 0, height.abs(), *txn_id));
         txns
     }
 
@@ -109,6 +148,13 @@ impl Status {
     }
 }
 
+/// A page of the confirmed history, ordered by (height, txid) - but not across pages
+/// (see `Query::history_page`).
+pub struct HistoryPage {
+    pub history: Vec<(u32, Sha256dHash)>, // funding transactions, and the ones spending them
+    pub next: Option<Bytes>, // index cursor of the last funding transaction, if more are left
+}
+
 struct TxnHeight {
     txn: Transaction,
     height: u32,
@@ -143,24 +189,45 @@ fn create_merkle_branch_and_root(
 // TODO: the functions below can be part of ReadStore.
 fn txrow_by_txid(store: &ReadStore, txid: &Sha256dHash) -> Option<TxRow> {
     let key = TxRow::filter_full(&txid);
-    let value = store.get(&key)?;
+    let value = store.get(Family::Tx, &key)?;
     Some(TxRow::from_row(&Row { key, value }))
 }
 
-fn txrows_by_prefix(store: &ReadStore, txid_prefix: &HashPrefix) -> Vec<TxRow> {
+fn txrows_by_prefix<'a>(
+    store: &'a ReadStore,
+    txid_prefix: &HashPrefix,
+) -> impl Iterator<Item = TxRow> + 'a {
     store
-        .scan(&TxRow::filter_prefix(&txid_prefix))
-        .iter()
-        .map(|row| TxRow::from_row(row))
-        .collect()
+        .scan(Family::Tx, &TxRow::filter_prefix(&txid_prefix))
+        .map(|row| TxRow::from_row(&row))
 }
 
-fn txids_by_script_hash(store: &ReadStore, script_hash: &[u8]) -> Vec<HashPrefix> {
+fn txids_by_script_hash<'a>(
+    store: &'a ReadStore,
+    script_hash: &[u8],
+) -> impl Iterator<Item = HashPrefix> + 'a {
     store
-        .scan(&TxOutRow::filter(script_hash))
-        .iter()
-        .map(|row| TxOutRow::from_row(row).txid_prefix)
-        .collect()
+        .scan(Family::TxOut, &TxOutRow::filter(script_hash))
+        .map(|row| TxOutRow::from_row(&row).txid_prefix)
+}
+
+// The UTXO rows are deleted when their outputs are spent, so only the unspent outputs are scanned
+// (having the full script hash, so there are no prefix collisions).
+fn unspent_outputs_by_script_hash<'a>(
+    store: &'a ReadStore,
+    script_hash: &[u8],
+) -> impl Iterator<Item = FundingOutput> + 'a {
+    store
+        .scan(Family::Utxo, &UtxoRow::filter(script_hash))
+        .map(|row| {
+            let utxo = UtxoRow::from_row(&row);
+            FundingOutput {
+                txn_id: deserialize(&utxo.key.txid).unwrap(),
+                height: utxo.height,
+                output_index: utxo.key.output_index as usize,
+                value: utxo.value,
+            }
+        })
 }
 
 fn txids_by_funding_output(
@@ -169,9 +236,8 @@ fn txids_by_funding_output(
     output_index: usize,
 ) -> Vec<HashPrefix> {
     store
-        .scan(&TxInRow::filter(&txn_id, output_index))
-        .iter()
-        .map(|row| TxInRow::from_row(row).txid_prefix)
+        .scan(Family::TxIn, &TxInRow::filter(&txn_id, output_index))
+        .map(|row| TxInRow::from_row(&row).txid_prefix)
         .collect()
 }
 
@@ -204,6 +270,7 @@ pub struct Query {
     tracker: RwLock<Tracker>,
     tx_cache: TransactionCache,
     txid_limit: usize,
+    mempool_changes: Mutex<Option<Changes>>, // since the last take_mempool_changes()
 }
 
 impl Query {
@@ -218,21 +285,26 @@ impl Query {
             tracker: RwLock::new(Tracker::new(metrics)),
             tx_cache,
             txid_limit,
+            mempool_changes: Mutex::new(None),
         })
     }
 
-    fn load_txns_by_prefix(
+    fn load_txns_by_prefix<I>(
         &self,
+        snapshot: &Snapshot,
         store: &ReadStore,
-        prefixes: Vec<HashPrefix>,
-    ) -> Result<Vec<TxnHeight>> {
+        prefixes: I,
+    ) -> Result<Vec<TxnHeight>>
+    where
+        I: IntoIterator<Item = HashPrefix>,
+    {
         let mut txns = vec![];
         for txid_prefix in prefixes {
             for tx_row in txrows_by_prefix(store, &txid_prefix) {
                 let txid: Sha256dHash = deserialize(&tx_row.key.txid).unwrap();
                 let txn = self
                     .tx_cache
-                    .get_or_else(&txid, || self.load_txn(&txid, tx_row.height))?;
+                    .get_or_else(&txid, || self.load_txn(snapshot, &txid, tx_row.height))?;
                 txns.push(TxnHeight {
                     txn,
                     height: tx_row.height,
@@ -244,10 +316,12 @@ impl Query {
 
     fn find_spending_input(
         &self,
+        snapshot: &Snapshot,
         store: &ReadStore,
         funding: &FundingOutput,
     ) -> Result<Option<SpendingInput>> {
         let spending_txns: Vec<TxnHeight> = self.load_txns_by_prefix(
+            snapshot,
             store,
             txids_by_funding_output(store, &funding.txn_id, funding.output_index),
         )?;
@@ -290,36 +364,66 @@ impl Query {
         result
     }
 
-    fn confirmed_status(
+    // Collects the scanned items, failing if there are more than `txid_limit` of them.
+    fn take_limited<I: Iterator>(&self, items: I) -> Result<Vec<I::Item>> {
+        let items: Vec<I::Item> = match self.txid_limit {
+            0 => items.collect(),
+            // if the limit is enabled, the scan is stopped right after exceeding it
+            txid_limit => items.take(txid_limit + 1).collect(),
+        };
+        if self.txid_limit > 0 && items.len() > self.txid_limit {
+            bail!(ErrorKind::TooManyTxs(items.len()));
+        }
+        Ok(items)
+    }
+
+    // All the transactions matching the script hash prefix are loaded (to filter out collisions).
+    fn confirmed_funding(
         &self,
+        snapshot: &Snapshot,
         script_hash: &[u8],
-    ) -> Result<(Vec<FundingOutput>, Vec<SpendingInput>)> {
+    ) -> Result<Vec<FundingOutput>> {
+        let read_store = snapshot.store();
+        let txid_prefixes = self.take_limited(txids_by_script_hash(read_store, script_hash))?;
         let mut funding = vec![];
-        let mut spending = vec![];
-        let read_store = self.app.read_store();
-        let txid_prefixes = txids_by_script_hash(read_store, script_hash);
-        // if the limit is enabled
-        if self.txid_limit > 0 {
-            if txid_prefixes.len() > self.txid_limit {
-                bail!(
-                    "{}+ transactions found, query may take a long time",
-                    txid_prefixes.len()
-                );
-            }
-        }
-        for t in self.load_txns_by_prefix(read_store, txid_prefixes)? {
+        for t in self.load_txns_by_prefix(snapshot, read_store, txid_prefixes)? {
             funding.extend(self.find_funding_outputs(&t, script_hash));
         }
+        Ok(funding)
+    }
+
+    fn confirmed_status(
+        &self,
+        snapshot: &Snapshot,
+        script_hash: &[u8],
+    ) -> Result<(Vec<FundingOutput>, Vec<SpendingInput>)> {
+        let mut spending = vec![];
+        let read_store = snapshot.store();
+        let funding = self.confirmed_funding(snapshot, script_hash)?;
         for funding_output in &funding {
-            if let Some(spent) = self.find_spending_input(read_store, &funding_output)? {
+            if let Some(spent) = self.find_spending_input(snapshot, read_store, &funding_output)? {
                 spending.push(spent);
             }
         }
         Ok((funding, spending))
     }
 
+    // Uses the UTXO rows, without loading any transaction (so `txid_limit` bounds the number of
+    // unspent outputs).
+    fn confirmed_unspent(
+        &self,
+        snapshot: &Snapshot,
+        script_hash: &[u8],
+    ) -> Result<Vec<FundingOutput>> {
+        self.take_limited(unspent_outputs_by_script_hash(
+            snapshot.store(),
+            script_hash,
+        ))
+    }
+
     fn mempool_status(
         &self,
+        snapshot: &Snapshot,
         script_hash: &[u8],
         confirmed_funding: &[FundingOutput],
     ) -> Result<(Vec<FundingOutput>, Vec<SpendingInput>)> {
@@ -327,30 +431,191 @@ impl Query {
         let mut spending = vec![];
         let tracker = self.tracker.read().unwrap();
         let txid_prefixes = txids_by_script_hash(tracker.index(), script_hash);
-        for t in self.load_txns_by_prefix(tracker.index(), txid_prefixes)? {
+        for t in self.load_txns_by_prefix(snapshot, tracker.index(), txid_prefixes)? {
             funding.extend(self.find_funding_outputs(&t, script_hash));
         }
         // // TODO: dedup outputs (somehow) both confirmed and in mempool (e.g. reorg?)
         for funding_output in funding.iter().chain(confirmed_funding.iter()) {
-            if let Some(spent) = self.find_spending_input(tracker.index(), &funding_output)? {
+            if let Some(spent) =
+                self.find_spending_input(snapshot, tracker.index(), &funding_output)?
+            {
                 spending.push(spent);
             }
         }
         Ok((funding, spending))
     }
 
+    fn mempool_txns(
+        &self,
+        (funding, spending): &(Vec<FundingOutput>, Vec<SpendingInput>),
+    ) -> HashMap<Sha256dHash, MempoolTxn> {
+        let tracker = self.tracker.read().unwrap();
+        let txids = funding.iter().map(|f| f.txn_id);
+        txids
+            .chain(spending.iter().map(|s| s.txn_id))
+            .filter_map(|txid| {
+                let fee = tracker.get_fee(&txid)?; // may be already removed from mempool
+                let unconfirmed_parents = tracker.unconfirmed_parents(&txid);
+                Some((
+                    txid,
+                    MempoolTxn {
+                        fee,
+                        unconfirmed_parents,
+                    },
+                ))
+            })
+            .collect()
+    }
+
     pub fn status(&self, script_hash: &[u8]) -> Result<Status> {
+        let snapshot = self.app.snapshot();
         let confirmed = self
-            .confirmed_status(script_hash)
+            .confirmed_status(&snapshot, script_hash)
             .chain_err(|| "failed to get confirmed status")?;
         let mempool = self
-            .mempool_status(script_hash, &confirmed.0)
+            .mempool_status(&snapshot, script_hash, &confirmed.0)
+            .chain_err(|| "failed to get mempool status")?;
+        let mempool_txns = self.mempool_txns(&mempool);
+        Ok(Status {
+            confirmed,
+            mempool,
+            mempool_txns,
+        })
+    }
+
+    /// Returns the mempool part of the status of `script_hash`, without loading its confirmed
+    /// transactions: so it doesn't include the mempool transactions spending only confirmed outputs.
+    pub fn mempool_only_status(&self, script_hash: &[u8]) -> Result<Status> {
+        let snapshot = self.app.snapshot();
+        let mempool = self
+            .mempool_status(&snapshot, script_hash, &[])
             .chain_err(|| "failed to get mempool status")?;
-        Ok(Status { confirmed, mempool })
+        let mempool_txns = self.mempool_txns(&mempool);
+        Ok(Status {
+            confirmed: (vec![], vec![]),
+            mempool,
+            mempool_txns,
+        })
+    }
+
+    /// Returns the status of `script_hash`, which is enough for computing its balance and unspent
+    /// outputs (but not its history): if the UTXO index is enabled, only its confirmed unspent
+    /// outputs are looked up, instead of loading all of its confirmed transactions.
+    pub fn unspent_status(&self, script_hash: &[u8]) -> Result<Status> {
+        if !self.app.index().has_utxos() {
+            return self.status(script_hash);
+        }
+        let snapshot = self.app.snapshot();
+        let unspent = self
+            .confirmed_unspent(&snapshot, script_hash)
+            .chain_err(|| "failed to get confirmed unspent outputs")?;
+        let confirmed = (unspent, vec![]);
+        let mempool = self
+            .mempool_status(&snapshot, script_hash, &confirmed.0)
+            .chain_err(|| "failed to get mempool status")?;
+        let mempool_txns = self.mempool_txns(&mempool);
+        Ok(Status {
+            confirmed,
+            mempool,
+            mempool_txns,
+        })
+    }
+
+    // Returns the funding outputs of the transactions matching up to `limit` txid prefixes
+    // (0 - all of them), following the `after` prefix in the TxOut rows' order, and the cursor
+    // of the last one if more are left. The prefixes of transactions confirmed below
+    // `from_height` are skipped using their Tx rows (without loading the transactions), so they
+    // don't count towards `limit`.
+    fn funding_page(
+        &self,
+        snapshot: &Snapshot,
+        script_hash: &[u8],
+        from_height: u32,
+        after: &[u8],
+        limit: usize,
+    ) -> Result<(Vec<Vec<FundingOutput>>, Option<Bytes>)> {
+        let store = snapshot.store();
+        let prefix = TxOutRow::filter(script_hash);
+        let start = [&prefix[..], after].concat();
+        let txid_prefixes = store
+            .scan_from(Family::TxOut, &prefix, &start)
+            .map(|row| TxOutRow::from_row(&row).txid_prefix)
+            .skip_while(|txid_prefix| txid_prefix[..] == *after);
+        let mut page: Vec<(HashPrefix, Vec<TxRow>)> = vec![];
+        let mut next = None;
+        for txid_prefix in txid_prefixes {
+            let tx_rows: Vec<TxRow> = txrows_by_prefix(store, &txid_prefix)
+                .filter(|tx_row| tx_row.height >= from_height)
+                .collect();
+            if tx_rows.is_empty() {
+                continue;
+            }
+            if limit > 0 && page.len() == limit {
+                next = Some(page[limit - 1].0.to_vec());
+                break;
+            }
+            page.push((txid_prefix, tx_rows));
+        }
+        let mut funding = vec![];
+        for tx_row in page.into_iter().flat_map(|(_, tx_rows)| tx_rows) {
+            let height = tx_row.height;
+            let txid: Sha256dHash = deserialize(&tx_row.key.txid).unwrap();
+            let txn = self
+                .tx_cache
+                .get_or_else(&txid, || self.load_txn(snapshot, &txid, height))?;
+            let outputs = self.find_funding_outputs(&TxnHeight { txn, height }, script_hash);
+            if !outputs.is_empty() {
+                funding.push(outputs); // otherwise, a txid prefix collision
+            }
+        }
+        Ok((funding, next))
+    }
+
+    /// Returns the confirmed history of `script_hash`, one page of funding transactions at a
+    /// time, in the order of their index rows (i.e. by txid prefix, not by height): following
+    /// the `after` cursor, up to `limit` of them (0 - as many as allowed), confirmed at
+    /// `from_height` or above. Each page resumes the index scan at its cursor, and only the
+    /// transactions of the page (and the ones spending their outputs) are loaded, so
+    /// `txid_limit` bounds the page size instead of the whole history - though the index rows
+    /// of the transactions confirmed below `from_height` are still scanned.
+    /// Transactions confirmed while paging may be ordered before the cursor, so they are found
+    /// by a later query (e.g. using `from_height`).
+    pub fn history_page(
+        &self,
+        script_hash: &[u8],
+        from_height: u32,
+        after: Option<Bytes>,
+        limit: usize,
+    ) -> Result<HistoryPage> {
+        let limit = match self.txid_limit {
+            0 => limit,
+            txid_limit if limit == 0 => txid_limit,
+            txid_limit => cmp::min(limit, txid_limit),
+        };
+        let snapshot = self.app.snapshot();
+        let read_store = snapshot.store();
+        let after = after.unwrap_or_default();
+        let (funding, next) =
+            self.funding_page(&snapshot, script_hash, from_height, &after, limit)?;
+        let mut history = vec![];
+        for outputs in funding {
+            history.push((outputs[0].height, outputs[0].txn_id));
+            for funding_output in &outputs {
+                if let Some(spent) =
+                    self.find_spending_input(&snapshot, read_store, funding_output)?
+                {
+                    history.push((spent.height, spent.txn_id));
+                }
+            }
+        }
+        history.sort_unstable();
+        history.dedup();
+        Ok(HistoryPage { history, next })
     }
 
     fn lookup_confirmed_blockhash(
         &self,
+        snapshot: &Snapshot,
         tx_hash: &Sha256dHash,
         block_height: Option<u32>,
     ) -> Result<Option<Sha256dHash>> {
@@ -361,14 +626,12 @@ impl Query {
             let height = match block_height {
                 Some(height) => height,
                 None => {
-                    txrow_by_txid(self.app.read_store(), &tx_hash)
+                    txrow_by_txid(snapshot.store(), &tx_hash)
                         .chain_err(|| format!("not indexed tx {}", tx_hash))?
                         .height
                 }
             };
-            let header = self
-                .app
-                .index()
+            let header = snapshot
                 .get_header(height as usize)
                 .chain_err(|| format!("missing header at height {}", height))?;
             Some(*header.hash())
@@ -377,29 +640,58 @@ impl Query {
     }
 
     // Internal API for transaction retrieval
-    fn load_txn(&self, tx_hash: &Sha256dHash, block_height: u32) -> Result<Transaction> {
-        let blockhash = self.lookup_confirmed_blockhash(tx_hash, Some(block_height))?;
+    fn load_txn(
+        &self,
+        snapshot: &Snapshot,
+        tx_hash: &Sha256dHash,
+        block_height: u32,
+    ) -> Result<Transaction> {
+        if let Some(txn) = self.read_stored_txn(snapshot, tx_hash) {
+            return Ok(txn);
+        }
+        let blockhash = self.lookup_confirmed_blockhash(snapshot, tx_hash, Some(block_height))?;
         self.app.daemon().gettransaction(tx_hash, blockhash)
     }
 
+    // Reads a confirmed transaction without JSONRPC (see `--store-txs` flag).
+    // Mempool transactions are not stored, so they are loaded from bitcoind.
+    fn read_stored_txn(&self, snapshot: &Snapshot, tx_hash: &Sha256dHash) -> Option<Transaction> {
+        if !self.app.index().has_txs() {
+            return None;
+        }
+        read_raw_tx(snapshot.store(), tx_hash)
+    }
+
     // Public API for transaction retrieval (for Electrum RPC)
     pub fn get_transaction(&self, tx_hash: &Sha256dHash, verbose: bool) -> Result<Value> {
-        let blockhash = self.lookup_confirmed_blockhash(tx_hash, /*block_height*/ None)?;
+        let snapshot = self.app.snapshot();
+        if !verbose {
+            if let Some(txn) = self.read_stored_txn(&snapshot, tx_hash) {
+                return Ok(Value::String(hex::encode(serialize(&txn))));
+            }
+        }
+        let blockhash =
+            self.lookup_confirmed_blockhash(&snapshot, tx_hash, /*block_height*/ None)?;
         self.app
             .daemon()
             .gettransaction_raw(tx_hash, blockhash, verbose)
     }
 
     pub fn get_headers(&self, heights: &[usize]) -> Vec<HeaderEntry> {
-        let index = self.app.index();
+        let snapshot = self.app.snapshot();
         heights
             .iter()
-            .filter_map(|height| index.get_header(*height))
+            .filter_map(|height| snapshot.get_header(*height))
             .collect()
     }
 
+    pub fn get_genesis_hash(&self) -> Result<Sha256dHash> {
+        let genesis = self.app.snapshot().get_header(0);
+        Ok(*genesis.chain_err(|| "no headers indexed")?.hash())
+    }
+
     pub fn get_best_header(&self) -> Result<HeaderEntry> {
-        let last_header = self.app.index().best_header();
+        let last_header = self.app.snapshot().best_header();
         Ok(last_header.chain_err(|| "no headers indexed")?.clone())
     }
 
@@ -410,7 +702,7 @@ impl Query {
     ) -> Result<(Vec<Sha256dHash>, usize)> {
         let header_entry = self
             .app
-            .index()
+            .snapshot()
             .get_header(height)
             .chain_err(|| format!("missing block #{}", height))?;
         let txids = self.app.daemon().getblocktxids(&header_entry.hash())?;
@@ -431,7 +723,11 @@ impl Query {
             bail!("cp_height #{} < height #{}", cp_height, height);
         }
 
-        let best_height = self.get_best_header()?.height();
+        let snapshot = self.app.snapshot();
+        let best_height = snapshot
+            .best_header()
+            .chain_err(|| "no headers indexed")?
+            .height();
         if best_height < cp_height {
             bail!(
                 "cp_height #{} above best block height #{}",
@@ -440,13 +736,11 @@ impl Query {
             );
         }
 
-        let heights: Vec<usize> = (0..cp_height + 1).collect();
-        let header_hashes: Vec<Sha256dHash> = self
-            .get_headers(&heights)
-            .into_iter()
+        let header_hashes: Vec<Sha256dHash> = (0..cp_height + 1)
+            .filter_map(|height| snapshot.get_header(height))
             .map(|h| *h.hash())
             .collect();
-        assert_eq!(header_hashes.len(), heights.len());
+        assert_eq!(header_hashes.len(), cp_height + 1);
         Ok(create_merkle_branch_and_root(header_hashes, height))
     }
 
@@ -458,7 +752,7 @@ impl Query {
     ) -> Result<(Sha256dHash, Vec<Sha256dHash>)> {
         let header_entry = self
             .app
-            .index()
+            .snapshot()
             .get_header(height)
             .chain_err(|| format!("missing block #{}", height))?;
 
@@ -480,7 +774,22 @@ impl Query {
     }
 
     pub fn update_mempool(&self) -> Result<()> {
-        self.tracker.write().unwrap().update(self.app.daemon())
+        let changes = self.tracker.write().unwrap().update(self.app.daemon())?;
+        self.mempool_changes
+            .lock()
+            .unwrap()
+            .get_or_insert_with(Changes::default)
+            .extend(changes);
+        Ok(())
+    }
+
+    /// Returns the mempool changes since the last call (including updates made by RPC handlers).
+    pub fn take_mempool_changes(&self) -> Changes {
+        self.mempool_changes
+            .lock()
+            .unwrap()
+            .take()
+            .unwrap_or_default()
     }
 
     /// Returns [vsize, fee_rate] pairs (measured in vbytes and satoshis).
@@ -507,3 +816,317 @@ impl Query {
         self.app.get_banner()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::blockdata::script::Script;
+    use bitcoin::blockdata::transaction::{OutPoint, Transaction, TxIn, TxOut};
+    use bitcoin::util::hash::Sha256dHash;
+    use std::fs;
+    use std::sync::Arc;
+
+    use crate::app::App;
+    use crate::config::Config;
+    use crate::daemon::Daemon;
+    use crate::fake::bitcoind::FakeBitcoind;
+    use crate::index::{compute_script_hash, read_raw_tx, Index};
+    use crate::metrics::Metrics;
+    use crate::query::{Query, TransactionCache};
+    use crate::signal::Waiter;
+    use crate::store::{DBStore, Family, ReadStore, WriteStore};
+    use crate::util::Bytes;
+
+    fn spend(txid: Sha256dHash, outputs: &[(&[u8], u64)]) -> Transaction {
+        Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint { txid, vout: 0 },
+                script_sig: Script::new(),
+                sequence: 0xffff_ffff,
+                witness: vec![],
+            }],
+            output: outputs
+                .iter()
+                .map(|(script, value)| TxOut {
+                    value: *value,
+                    script_pubkey: Script::from(script.to_vec()),
+                })
+                .collect(),
+        }
+    }
+
+    // Indexes the daemon's blocks into a new DB.
+    fn start(config: &Config, daemon: Daemon, utxos: bool, txs: bool) -> (Arc<App>, Arc<Query>) {
+        let metrics = Metrics::new(config.monitoring_addr);
+        let store = DBStore::open(
+            &config.db_path,
+            /*low_memory=*/ false,
+            config.db_cache_size,
+        )
+        .unwrap();
+        let index = Index::load(&store, &daemon, &metrics, 2, utxos, txs).unwrap();
+        let app = App::new(store, index, daemon, config).unwrap();
+        let tx_cache = TransactionCache::new(100);
+        let query = Query::new(app.clone(), &metrics, tx_cache, config.txid_limit);
+        app.update(&Waiter::new()).unwrap();
+        (app, query)
+    }
+
+    // Returns the (confirmed balance, history, unspent outputs) of `script`.
+    fn summary(query: &Query, script: &[u8]) -> (i64, Vec<(i32, Sha256dHash)>, Vec<u64>) {
+        let script_hash = compute_script_hash(script);
+        let status = query.status(&script_hash).unwrap();
+        let unspent = query.unspent_status(&script_hash).unwrap();
+        assert_eq!(status.confirmed_balance(), unspent.confirmed_balance());
+        let values = unspent.unspent().iter().map(|out| out.value).collect();
+        (status.confirmed_balance(), status.history(), values)
+    }
+
+    #[test]
+    fn test_reorg() {
+        let bitcoind = FakeBitcoind::start();
+        let config = bitcoind.config("reorg");
+        let daemon = FakeBitcoind::daemon(&config);
+
+        // block 1 and 2 pay to "alice", block 3 spends the latter to "bob" (returning the change)
+        let mine_to = |script: &[u8]| daemon.getblock(&bitcoind.mine_to(script, vec![])).unwrap();
+        let first = mine_to(b"alice").txdata[0].txid();
+        let funding = mine_to(b"alice").txdata[0].txid();
+        let payment = spend(funding, &[(b"bob", 30), (b"alice", 20)]);
+        bitcoind.mine(vec![payment.clone()]);
+        bitcoind.mine(vec![]);
+
+        let (app, query) = start(&config, daemon, /*utxos=*/ true, /*txs=*/ true);
+        assert_eq!(query.get_best_header().unwrap().height(), 4);
+        let stored = || read_raw_tx(app.snapshot().store(), &payment.txid());
+        assert_eq!(stored(), Some(payment.clone()));
+        let alice = vec![(1, first), (2, funding), (3, payment.txid())];
+        assert_eq!(summary(&query, b"alice"), (70, alice, vec![50, 20]));
+        assert_eq!(
+            summary(&query, b"bob"),
+            (30, vec![(3, payment.txid())], vec![30])
+        );
+
+        // replace blocks 3 and 4 by three new blocks, confirming the payment again at height 5
+        bitcoind.invalidate(2);
+        bitcoind.mine_to(b"carol", vec![]);
+        bitcoind.mine(vec![]);
+        bitcoind.mine(vec![payment.clone()]);
+        assert!(!app.update(&Waiter::new()).unwrap().is_empty());
+        assert_eq!(query.get_best_header().unwrap().height(), 5);
+        let alice = vec![(1, first), (2, funding), (5, payment.txid())];
+        assert_eq!(summary(&query, b"alice"), (70, alice, vec![50, 20]));
+        assert_eq!(
+            summary(&query, b"bob"),
+            (30, vec![(5, payment.txid())], vec![30])
+        );
+        assert_eq!(summary(&query, b"carol").0, 50);
+
+        // replace blocks 2..5 by a single block, orphaning both the funding and the payment
+        bitcoind.invalidate(4);
+        bitcoind.mine(vec![]);
+        app.update(&Waiter::new()).unwrap();
+        assert_eq!(query.get_best_header().unwrap().height(), 2);
+        assert_eq!(summary(&query, b"alice"), (50, vec![(1, first)], vec![50]));
+        assert_eq!(summary(&query, b"bob"), (0, vec![], vec![]));
+        assert_eq!(summary(&query, b"carol"), (0, vec![], vec![]));
+        assert_eq!(stored(), None);
+        fs::remove_dir_all(&config.db_path).unwrap();
+    }
+
+    #[test]
+    fn test_reorg_without_undo() {
+        let bitcoind = FakeBitcoind::start();
+        let config = bitcoind.config("reorg-no-undo");
+        let daemon = FakeBitcoind::daemon(&config);
+        let mine_to = |script: &[u8]| daemon.getblock(&bitcoind.mine_to(script, vec![])).unwrap();
+        let funding = mine_to(b"alice").txdata[0].txid();
+        let payment = spend(funding, &[(b"bob", 30), (b"alice", 20)]);
+        bitcoind.mine(vec![payment.clone()]);
+
+        // index the blocks, and drop their undo data (e.g. a DB migrated from an older version)
+        // - which is required by the UTXO index, for restoring the spent outputs
+        {
+            let store = DBStore::open(&config.db_path, false, config.db_cache_size).unwrap();
+            let metrics = Metrics::new(config.monitoring_addr);
+            let index = Index::load(&store, &daemon, &metrics, 2, false, true).unwrap();
+            index.update(&store, &Waiter::new()).unwrap();
+            let keys: Vec<Bytes> = store.scan(Family::Undo, b"").map(|row| row.key).collect();
+            assert_eq!(keys.len(), 3);
+            store.delete_and_write(keys, vec![]);
+        }
+        let (app, query) = start(&config, daemon, /*utxos=*/ false, /*txs=*/ true);
+        assert_eq!(summary(&query, b"bob").0, 30);
+
+        // orphan the payment, which is fetched from the daemon for deleting its rows
+        bitcoind.invalidate(1);
+        bitcoind.mine(vec![]);
+        app.update(&Waiter::new()).unwrap();
+        assert_eq!(query.get_best_header().unwrap().height(), 2);
+        let alice = (50, vec![(1, funding)], vec![50]);
+        assert_eq!(summary(&query, b"alice"), alice);
+        assert_eq!(summary(&query, b"bob"), (0, vec![], vec![]));
+        assert_eq!(read_raw_tx(app.snapshot().store(), &payment.txid()), None);
+        fs::remove_dir_all(&config.db_path).unwrap();
+    }
+
+    #[test]
+    fn test_unspent_from_utxos() {
+        let bitcoind = FakeBitcoind::start();
+        let tx_config = bitcoind.config("tx-funding");
+        let daemon = FakeBitcoind::daemon(&tx_config);
+        bitcoind.mine_to(b"alice", vec![]);
+        let blockhash = bitcoind.mine_to(b"alice", vec![]);
+        let funding = daemon.getblock(&blockhash).unwrap().txdata[0].txid();
+        bitcoind.mine(vec![spend(funding, &[(b"bob", 30), (b"alice", 20)])]);
+
+        // the summaries and the first history pages (of up to 2 funding transactions)
+        let results = |config: &Config, daemon: Daemon, utxos: bool| {
+            let (_app, query) = start(config, daemon, utxos, /*txs=*/ false);
+            let pages: Vec<_> = [&b"alice"[..], &b"bob"[..]]
+                .iter()
+                .map(|script| {
+                    let script_hash = compute_script_hash(script);
+                    let page = query.history_page(&script_hash, 0, None, 2).unwrap();
+                    (summary(&query, script), page.history, page.next.is_some())
+                })
+                .collect();
+            fs::remove_dir_all(&config.db_path).unwrap();
+            pages
+        };
+        let loaded = results(&tx_config, daemon, /*utxos=*/ false);
+        // the unspent outputs are read from the UTXO rows, instead of the loaded transactions
+        let utxo_config = bitcoind.config("utxo-funding");
+        let daemon = FakeBitcoind::daemon(&utxo_config);
+        let indexed = results(&utxo_config, daemon, /*utxos=*/ true);
+        assert_eq!(loaded, indexed);
+        assert_eq!((indexed[0].0).0, 70);
+        assert!(indexed[0].2); // alice has 3 funding transactions
+        assert_eq!((indexed[1].0).0, 30);
+    }
+
+    #[test]
+    fn test_history_pages() {
+        let bitcoind = FakeBitcoind::start();
+        let daemon = FakeBitcoind::daemon(&bitcoind.config("history-pages"));
+        let mut alice: Vec<(u32, Sha256dHash)> = (1..=5)
+            .map(|height| {
+                let blockhash = bitcoind.mine_to(b"alice", vec![]);
+                (
+                    height,
+                    daemon.getblock(&blockhash).unwrap().txdata[0].txid(),
+                )
+            })
+            .collect();
+        let payment = spend(alice[0].1, &[(b"bob", 50)]);
+        bitcoind.mine(vec![payment.clone()]);
+        alice.push((6, payment.txid()));
+
+        let script_hash = compute_script_hash(b"alice");
+        for &utxos in &[false, true] {
+            let mut config = bitcoind.config(&format!("history-pages-{}", utxos));
+            config.txid_limit = 2;
+            let daemon = FakeBitcoind::daemon(&config);
+            let (_app, query) = start(&config, daemon, utxos, /*txs=*/ false);
+            assert!(query.status(&script_hash).is_err()); // more than 2 funding transactions
+
+            // returns the history (and the number of pages), following the pages' cursors
+            let pages = |from_height| {
+                let (mut history, mut count, mut cursor) = (vec![], 0, None);
+                query.tx_cache.map.lock().unwrap().clear();
+                loop {
+                    let page = query
+                        .history_page(&script_hash, from_height, cursor, 0)
+                        .unwrap();
+                    if count == 0 {
+                        // the funding transactions of the first page, and the spending one
+                        assert!(query.tx_cache.map.lock().unwrap().len() <= 3);
+                    }
+                    history.extend(page.history);
+                    count += 1;
+                    cursor = page.next;
+                    if cursor.is_none() {
+                        break;
+                    }
+                }
+                history.sort_unstable();
+                history.dedup();
+                (history, count)
+            };
+            assert_eq!(pages(0), (alice.clone(), 3));
+            // the funding transactions below height 4 don't count towards the limit
+            assert_eq!(pages(4), (alice[3..5].to_vec(), 1));
+            fs::remove_dir_all(&config.db_path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_mempool_only_status() {
+        let bitcoind = FakeBitcoind::start();
+        let mut config = bitcoind.config("mempool-only");
+        config.txid_limit = 2;
+        let daemon = FakeBitcoind::daemon(&config);
+        let funding: Vec<Sha256dHash> = (0..3)
+            .map(|_| {
+                let blockhash = bitcoind.mine_to(b"alice", vec![]);
+                daemon.getblock(&blockhash).unwrap().txdata[0].txid()
+            })
+            .collect();
+        let payment = spend(funding[0], &[(b"bob", 30), (b"alice", 20)]);
+        bitcoind.send(payment.clone());
+
+        let (_app, query) = start(&config, daemon, /*utxos=*/ true, /*txs=*/ false);
+        query.update_mempool().unwrap();
+        let script_hash = compute_script_hash(b"alice");
+        assert!(query.status(&script_hash).is_err()); // more than 2 funding transactions
+        let status = query.mempool_only_status(&script_hash).unwrap();
+        assert_eq!(status.history(), vec![(0, payment.txid())]);
+        assert_eq!(status.fee(&payment.txid()), Some(1000));
+        fs::remove_dir_all(&config.db_path).unwrap();
+    }
+
+    #[test]
+    fn test_unspent_limit() {
+        let bitcoind = FakeBitcoind::start();
+        let mut config = bitcoind.config("unspent-limit");
+        config.txid_limit = 2;
+        let daemon = FakeBitcoind::daemon(&config);
+        let funding = daemon
+            .getblock(&bitcoind.mine_to(b"alice", vec![]))
+            .unwrap();
+        bitcoind.mine_to(b"alice", vec![]);
+        bitcoind.mine(vec![spend(
+            funding.txdata[0].txid(),
+            &[(b"bob", 30), (b"alice", 20)],
+        )]);
+
+        // the spent output is not scanned, so only 2 of the 3 outputs count towards the limit
+        let (app, query) = start(&config, daemon, /*utxos=*/ true, /*txs=*/ false);
+        let unspent = |script: &[u8]| query.unspent_status(&compute_script_hash(script));
+        let values = |script: &[u8]| -> Vec<u64> {
+            let status = unspent(script).unwrap();
+            let mut values: Vec<u64> = status.unspent().iter().map(|out| out.value).collect();
+            values.sort_unstable();
+            values
+        };
+        assert_eq!(values(b"alice"), vec![20, 50]);
+        assert_eq!(values(b"bob"), vec![30]);
+
+        // orphaning the spending block restores the spent output
+        bitcoind.invalidate(1);
+        bitcoind.mine(vec![]);
+        bitcoind.mine(vec![]);
+        app.update(&Waiter::new()).unwrap();
+        assert_eq!(values(b"alice"), vec![50, 50]);
+        assert_eq!(values(b"bob"), Vec::<u64>::new());
+
+        bitcoind.mine_to(b"alice", vec![]);
+        app.update(&Waiter::new()).unwrap();
+        let err = unspent(b"alice").err().unwrap();
+        assert!(err
+            .iter()
+            .any(|e| e.to_string().contains("3+ transactions")));
+        fs::remove_dir_all(&config.db_path).unwrap();
+    }
+}
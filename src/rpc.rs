@@ -4,29 +4,166 @@ use bitcoin::util::hash::Sha256dHash;
 use error_chain::ChainedError;
 use hex;
 use serde_json::{from_str, Value};
-use std::collections::HashMap;
-use std::io::{BufRead, BufReader, Write};
-use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
-use std::sync::mpsc::{Sender, SyncSender, TrySendError};
-use std::sync::{Arc, Mutex};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::Arc;
 use std::thread;
 
+use crate::config::Config;
 use crate::errors::*;
-use crate::metrics::{Gauge, HistogramOpts, HistogramVec, MetricOpts, Metrics};
+use crate::index::Changes;
+use crate::metrics::{
+    CounterVec, Gauge, GaugeVec, HistogramOpts, HistogramVec, MetricOpts, Metrics,
+};
+use crate::peers::{PeerAddr, Peers};
 use crate::query::{Query, Status};
-use crate::util::{spawn_thread, Channel, HeaderEntry, SyncChannel};
+use crate::tls::TlsAcceptor;
+use crate::transport::{self, Event, EventLoop, Output, Transport};
+use crate::util::{spawn_thread, Bytes, Channel, HashPrefix, HeaderEntry};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct ProtocolVersion {
+    major: usize,
+    minor: usize,
+    patch: usize,
+}
+
+impl ProtocolVersion {
+    const fn new(major: usize, minor: usize, patch: usize) -> ProtocolVersion {
+        ProtocolVersion {
+            major,
+            minor,
+            patch,
+        }
+    }
+
+    fn parse(version: &str) -> Result<ProtocolVersion> {
+        let parts = version
+            .split('.')
+            .map(|part| part.parse::<usize>())
+            .collect::<std::result::Result<Vec<usize>, _>>()
+            .chain_err(|| bad_request(format!("invalid protocol version {:?}", version)))?;
+        match parts[..] {
+            [major, minor] => Ok(ProtocolVersion::new(major, minor, 0)),
+            [major, minor, patch] => Ok(ProtocolVersion::new(major, minor, patch)),
+            _ => bail!(bad_request(format!(
+                "invalid protocol version {:?}",
+                version
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for ProtocolVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)?;
+        if self.patch > 0 {
+            write!(f, ".{}", self.patch)?;
+        }
+        Ok(())
+    }
+}
+
+// JSON-RPC 2.0 error codes
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INTERNAL_ERROR: i64 = -32603;
+// Electrum application error codes (compatible with ElectrumX)
+const BAD_REQUEST: i64 = 1;
+const DAEMON_ERROR: i64 = 2;
+const EXCESSIVE_RESOURCE_USAGE: i64 = -101;
+
+const SERVER_VERSION: &str = "RustElectrum 0.1.0";
+const PROTOCOL_VERSION_MIN: ProtocolVersion = ProtocolVersion::new(1, 4, 0);
+const PROTOCOL_VERSION_MAX: ProtocolVersion = ProtocolVersion::new(1, 4, 2);
+
+/// Picks the highest supported version within the client's `[min, max]` range.
+fn negotiate_version(min: ProtocolVersion, max: ProtocolVersion) -> Result<ProtocolVersion> {
+    let version = std::cmp::min(max, PROTOCOL_VERSION_MAX);
+    if version < std::cmp::max(min, PROTOCOL_VERSION_MIN) {
+        bail!(bad_request(format!(
+            "unsupported protocol version range [{}, {}] (supported: [{}, {}])",
+            min, max, PROTOCOL_VERSION_MIN, PROTOCOL_VERSION_MAX
+        )));
+    }
+    Ok(version)
+}
+
+fn version_from_value(val: &Value) -> Result<ProtocolVersion> {
+    ProtocolVersion::parse(
+        val.as_str()
+            .chain_err(|| bad_request("non-string protocol version"))?,
+    )
+}
+
+fn bad_request<S: Into<String>>(msg: S) -> ErrorKind {
+    ErrorKind::BadRequest(msg.into())
+}
+
+/// Returns the JSON-RPC error object for `e`, using the code of the first typed error in its chain.
+fn error_value(e: &Error) -> Value {
+    let mut code = INTERNAL_ERROR;
+    let mut message = e.to_string();
+    let mut next = Some(e);
+    while let Some(err) = next {
+        code = match err.kind() {
+            ErrorKind::InvalidRequest(_) => INVALID_REQUEST,
+            ErrorKind::MethodNotFound(_) => METHOD_NOT_FOUND,
+            ErrorKind::BadRequest(_) => BAD_REQUEST,
+            ErrorKind::Daemon(..) => {
+                message = err.to_string();
+                DAEMON_ERROR
+            }
+            ErrorKind::Rejected(daemon_code, msg) => {
+                message = msg.clone();
+                *daemon_code
+            }
+            ErrorKind::TooManyTxs(_) | ErrorKind::ResourceLimit(_) => EXCESSIVE_RESOURCE_USAGE,
+            _ => {
+                next = err.1.next_error.as_ref().and_then(|e| e.downcast_ref());
+                continue;
+            }
+        };
+        break;
+    }
+    json!({"code": code, "message": message})
+}
+
+/// Passes the `bitcoind` error code and message of a rejected transaction broadcast to the client.
+fn rejection(e: Error) -> Error {
+    let mut next = Some(&e);
+    while let Some(err) = next {
+        if let ErrorKind::Daemon(code, msg) = err.kind() {
+            return ErrorKind::Rejected(*code, msg.clone()).into();
+        }
+        next = err.1.next_error.as_ref().and_then(|e| e.downcast_ref());
+    }
+    e
+}
+
+fn error_reply(id: &Value, e: &Error) -> Value {
+    json!({"jsonrpc": "2.0", "id": id, "error": error_value(e)})
+}
 
 // TODO: Sha256dHash should be a generic hash-container (since script hash is single SHA256)
 fn hash_from_value(val: Option<&Value>) -> Result<Sha256dHash> {
-    let script_hash = val.chain_err(|| "missing hash")?;
-    let script_hash = script_hash.as_str().chain_err(|| "non-string hash")?;
-    let script_hash = Sha256dHash::from_hex(script_hash).chain_err(|| "non-hex hash")?;
+    let script_hash = val.chain_err(|| bad_request("missing hash"))?;
+    let script_hash = script_hash
+        .as_str()
+        .chain_err(|| bad_request("non-string hash"))?;
+    let script_hash =
+        Sha256dHash::from_hex(script_hash).chain_err(|| bad_request("non-hex hash"))?;
     Ok(script_hash)
 }
 
 fn usize_from_value(val: Option<&Value>, name: &str) -> Result<usize> {
-    let val = val.chain_err(|| format!("missing {}", name))?;
-    let val = val.as_u64().chain_err(|| format!("non-integer {}", name))?;
+    let val = val.chain_err(|| bad_request(format!("missing {}", name)))?;
+    let val = val
+        .as_u64()
+        .chain_err(|| bad_request(format!("non-integer {}", name)))?;
     Ok(val as usize)
 }
 
@@ -37,9 +174,19 @@ fn usize_from_value_or(val: Option<&Value>, name: &str, default: usize) -> Resul
     usize_from_value(val, name)
 }
 
+fn u32_from_value_or(val: Option<&Value>, name: &str, default: u32) -> Result<u32> {
+    let val = usize_from_value_or(val, name, default as usize)?;
+    if val > u32::MAX as usize {
+        bail!(bad_request(format!("too large {}", name)));
+    }
+    Ok(val as u32)
+}
+
 fn bool_from_value(val: Option<&Value>, name: &str) -> Result<bool> {
-    let val = val.chain_err(|| format!("missing {}", name))?;
-    let val = val.as_bool().chain_err(|| format!("not a bool {}", name))?;
+    let val = val.chain_err(|| bad_request(format!("missing {}", name)))?;
+    let val = val
+        .as_bool()
+        .chain_err(|| bad_request(format!("not a bool {}", name)))?;
     Ok(val)
 }
 
@@ -50,6 +197,49 @@ fn bool_from_value_or(val: Option<&Value>, name: &str, default: bool) -> Result<
     bool_from_value(val, name)
 }
 
+// History pages are continued using opaque (hex-encoded) index cursors.
+fn cursor_from_value(val: Option<&Value>) -> Result<Option<Bytes>> {
+    let cursor = match val {
+        None | Some(Value::Null) => return Ok(None),
+        Some(val) => val
+            .as_str()
+            .chain_err(|| bad_request("non-string cursor"))?,
+    };
+    let cursor = hex::decode(cursor).chain_err(|| bad_request("non-hex cursor"))?;
+    Ok(Some(cursor))
+}
+
+fn cursor_to_value(cursor: Option<Bytes>) -> Value {
+    cursor.map_or(Value::Null, |cursor| json!(hex::encode(cursor)))
+}
+
+fn parse_command(cmd: &Value) -> Option<(&str, &[Value], Option<&Value>)> {
+    let method = cmd.get("method")?.as_str()?;
+    let params = match cmd.get("params") {
+        Some(Value::Array(params)) => &params[..],
+        Some(_) => return None,
+        None => &[],
+    };
+    Some((method, params, cmd.get("id")))
+}
+
+fn history_from_status(status: &Status, mempool_only: bool) -> Value {
+    json!(Value::Array(
+        status
+            .history()
+            .into_iter()
+            .filter(|(height, _)| !mempool_only || *height <= 0)
+            .map(|(height, txn_id)| {
+                let mut item = json!({"height": height, "tx_hash": txn_id.be_hex_string()});
+                if let Some(fee) = status.fee(&txn_id) {
+                    item["fee"] = json!(fee); // only for mempool transactions
+                }
+                item
+            })
+            .collect()
+    ))
+}
+
 fn unspent_from_status(status: &Status) -> Value {
     json!(Value::Array(
         status
@@ -65,30 +255,65 @@ fn unspent_from_status(status: &Status) -> Value {
     ))
 }
 
+/// Limits on the resources a client may use (0 - disable the limit).
+#[derive(Clone, Copy)]
+struct Limits {
+    batch: usize,
+    connection_subscriptions: usize,
+    server_subscriptions: usize,
+}
+
+/// Server-wide settings, shared by all connections.
+struct Settings {
+    limits: Limits,
+    features: Value,           // reported via server.features
+    peers: Option<Arc<Peers>>, // if peer discovery is enabled
+}
+
+struct Subscription {
+    status_hash: Value,
+    related_txids: HashSet<HashPrefix>, // for detecting spent outputs and confirmed parents
+}
+
+impl Subscription {
+    fn new(status: &Status) -> Subscription {
+        Subscription {
+            status_hash: status.hash().map_or(Value::Null, |h| json!(hex::encode(h))),
+            related_txids: status.related_txids(),
+        }
+    }
+}
+
 struct Connection {
     query: Arc<Query>,
     last_header_entry: Option<HeaderEntry>,
-    status_hashes: HashMap<Sha256dHash, Value>, // ScriptHash -> StatusHash
-    stream: TcpStream,
+    subscriptions: HashMap<Sha256dHash, Subscription>, // by ScriptHash
     addr: SocketAddr,
-    chan: SyncChannel<Message>,
+    transport: &'static str, // for labeling the metrics
+    settings: Arc<Settings>,
+    protocol_version: ProtocolVersion, // negotiated via server.version
+    update_pending: bool,              // e.g. after a transaction broadcast
     stats: Arc<Stats>,
 }
 
 impl Connection {
     pub fn new(
         query: Arc<Query>,
-        stream: TcpStream,
         addr: SocketAddr,
+        transport: &'static str,
+        settings: Arc<Settings>,
         stats: Arc<Stats>,
     ) -> Connection {
+        stats.connections.with_label_values(&[transport]).inc();
         Connection {
             query,
             last_header_entry: None, // disable header subscription for now
-            status_hashes: HashMap::new(),
-            stream,
+            subscriptions: HashMap::new(),
             addr,
-            chan: SyncChannel::new(10),
+            transport,
+            settings,
+            protocol_version: PROTOCOL_VERSION_MIN,
+            update_pending: false,
             stats,
         }
     }
@@ -101,20 +326,59 @@ impl Connection {
         Ok(result)
     }
 
-    fn server_version(&self) -> Result<Value> {
-        Ok(json!(["RustElectrum 0.1.0", "1.4"]))
+    fn server_version(&mut self, params: &[Value]) -> Result<Value> {
+        let client_name = match params.get(0) {
+            Some(name) => name
+                .as_str()
+                .chain_err(|| bad_request("non-string client name"))?,
+            None => "",
+        };
+        let (min, max) = match params.get(1) {
+            None => (PROTOCOL_VERSION_MIN, PROTOCOL_VERSION_MIN),
+            Some(Value::Array(range)) if range.len() == 2 => (
+                version_from_value(&range[0])?,
+                version_from_value(&range[1])?,
+            ),
+            Some(version) => {
+                let version = version_from_value(version)?;
+                (version, version)
+            }
+        };
+        self.protocol_version = negotiate_version(min, max)?;
+        debug!(
+            "[{}] client {:?} uses protocol version {}",
+            self.addr, client_name, self.protocol_version
+        );
+        Ok(json!([SERVER_VERSION, self.protocol_version.to_string()]))
     }
 
     fn server_banner(&self) -> Result<Value> {
         Ok(json!(self.query.get_banner()?))
     }
 
+    fn server_features(&self) -> Result<Value> {
+        Ok(self.settings.features.clone())
+    }
+
+    fn server_add_peer(&self, params: &[Value]) -> Result<Value> {
+        let features = params
+            .get(0)
+            .chain_err(|| bad_request("missing features"))?;
+        Ok(json!(match self.settings.peers {
+            Some(ref peers) => peers.add(features, self.addr.ip())?,
+            None => false,
+        }))
+    }
+
     fn server_donation_address(&self) -> Result<Value> {
         Ok(Value::Null)
     }
 
     fn server_peers_subscribe(&self) -> Result<Value> {
-        Ok(json!([]))
+        Ok(match self.settings.peers {
+            Some(ref peers) => peers.get_peers(),
+            None => json!([]),
+        })
     }
 
     fn mempool_get_fee_histogram(&self) -> Result<Value> {
@@ -193,15 +457,70 @@ impl Connection {
 
     fn blockchain_scripthash_subscribe(&mut self, params: &[Value]) -> Result<Value> {
         let script_hash = hash_from_value(params.get(0)).chain_err(|| "bad script_hash")?;
-        let status = self.query.status(&script_hash[..])?;
-        let result = status.hash().map_or(Value::Null, |h| json!(hex::encode(h)));
-        self.status_hashes.insert(script_hash, result.clone());
+        let added = !self.subscriptions.contains_key(&script_hash);
+        if added {
+            self.add_subscription()?; // check the limits before computing the status
+        }
+        let status = match self.query.status(&script_hash[..]) {
+            Ok(status) => status,
+            Err(e) => {
+                if added {
+                    self.remove_subscriptions(1);
+                }
+                return Err(e);
+            }
+        };
+        let subscription = Subscription::new(&status);
+        let result = subscription.status_hash.clone();
+        self.subscriptions.insert(script_hash, subscription);
         Ok(result)
     }
 
+    fn blockchain_scripthash_unsubscribe(&mut self, params: &[Value]) -> Result<Value> {
+        let script_hash = hash_from_value(params.get(0)).chain_err(|| "bad script_hash")?;
+        let removed = self.subscriptions.remove(&script_hash).is_some();
+        if removed {
+            self.remove_subscriptions(1);
+        }
+        Ok(json!(removed))
+    }
+
+    fn add_subscription(&mut self) -> Result<()> {
+        let limit = self.settings.limits.connection_subscriptions;
+        if limit > 0 && self.subscriptions.len() >= limit {
+            bail!(ErrorKind::ResourceLimit(format!(
+                "too many subscriptions on this connection (limit: {})",
+                limit
+            )));
+        }
+        let limit = self.settings.limits.server_subscriptions;
+        let total = self
+            .stats
+            .server_subscriptions
+            .fetch_add(1, Ordering::SeqCst);
+        if limit > 0 && total >= limit {
+            self.stats
+                .server_subscriptions
+                .fetch_sub(1, Ordering::SeqCst);
+            bail!(ErrorKind::ResourceLimit(format!(
+                "too many subscriptions on this server (limit: {})",
+                limit
+            )));
+        }
+        self.stats.subscriptions.inc();
+        Ok(())
+    }
+
+    fn remove_subscriptions(&mut self, count: usize) {
+        self.stats
+            .server_subscriptions
+            .fetch_sub(count, Ordering::SeqCst);
+        self.stats.subscriptions.sub(count as i64);
+    }
+
     fn blockchain_scripthash_get_balance(&self, params: &[Value]) -> Result<Value> {
         let script_hash = hash_from_value(params.get(0)).chain_err(|| "bad script_hash")?;
-        let status = self.query.status(&script_hash[..])?;
+        let status = self.query.unspent_status(&script_hash[..])?;
         Ok(
             json!({ "confirmed": status.confirmed_balance(), "unconfirmed": status.mempool_balance() }),
         )
@@ -210,37 +529,55 @@ impl Connection {
     fn blockchain_scripthash_get_history(&self, params: &[Value]) -> Result<Value> {
         let script_hash = hash_from_value(params.get(0)).chain_err(|| "bad script_hash")?;
         let status = self.query.status(&script_hash[..])?;
-        Ok(json!(Value::Array(
-            status
-                .history()
-                .into_iter()
-                .map(|item| json!({"height": item.0, "tx_hash": item.1.be_hex_string()}))
-                .collect()
-        )))
+        Ok(history_from_status(&status, false))
+    }
+
+    fn blockchain_scripthash_get_history_page(&self, params: &[Value]) -> Result<Value> {
+        let script_hash = hash_from_value(params.get(0)).chain_err(|| "bad script_hash")?;
+        let from_height = u32_from_value_or(params.get(1), "from_height", 0)?;
+        let limit = usize_from_value_or(params.get(2), "limit", 0)?;
+        let after = cursor_from_value(params.get(3))?;
+        let page = self
+            .query
+            .history_page(&script_hash[..], from_height, after, limit)?;
+        let history: Vec<Value> = page
+            .history
+            .into_iter()
+            .map(|(height, txn_id)| json!({"height": height, "tx_hash": txn_id.be_hex_string()}))
+            .collect();
+        Ok(json!({"history": history, "next": cursor_to_value(page.next)}))
+    }
+
+    fn blockchain_scripthash_get_mempool(&self, params: &[Value]) -> Result<Value> {
+        let script_hash = hash_from_value(params.get(0)).chain_err(|| "bad script_hash")?;
+        let status = self.query.mempool_only_status(&script_hash[..])?;
+        Ok(history_from_status(&status, true))
     }
 
     fn blockchain_scripthash_listunspent(&self, params: &[Value]) -> Result<Value> {
         let script_hash = hash_from_value(params.get(0)).chain_err(|| "bad script_hash")?;
-        Ok(unspent_from_status(&self.query.status(&script_hash[..])?))
+        Ok(unspent_from_status(
+            &self.query.unspent_status(&script_hash[..])?,
+        ))
     }
 
-    fn blockchain_transaction_broadcast(&self, params: &[Value]) -> Result<Value> {
-        let tx = params.get(0).chain_err(|| "missing tx")?;
-        let tx = tx.as_str().chain_err(|| "non-string tx")?;
-        let tx = hex::decode(&tx).chain_err(|| "non-hex tx")?;
-        let tx: Transaction = deserialize(&tx).chain_err(|| "failed to parse tx")?;
-        let txid = self.query.broadcast(&tx)?;
+    fn blockchain_transaction_broadcast(&mut self, params: &[Value]) -> Result<Value> {
+        let tx = params.get(0).chain_err(|| bad_request("missing tx"))?;
+        let tx = tx.as_str().chain_err(|| bad_request("non-string tx"))?;
+        let tx = hex::decode(&tx).chain_err(|| bad_request("non-hex tx"))?;
+        let tx: Transaction = deserialize(&tx).chain_err(|| bad_request("failed to parse tx"))?;
+        let txid = self.query.broadcast(&tx).map_err(rejection)?;
         self.query.update_mempool()?;
-        if let Err(e) = self.chan.sender().try_send(Message::PeriodicUpdate) {
-            warn!("failed to issue PeriodicUpdate after broadcast: {}", e);
-        }
+        self.update_pending = true;
         Ok(json!(txid.be_hex_string()))
     }
 
     fn blockchain_transaction_get(&self, params: &[Value]) -> Result<Value> {
         let tx_hash = hash_from_value(params.get(0)).chain_err(|| "bad tx_hash")?;
         let verbose = match params.get(1) {
-            Some(value) => value.as_bool().chain_err(|| "non-bool verbose value")?,
+            Some(value) => value
+                .as_bool()
+                .chain_err(|| bad_request("non-bool verbose value"))?,
             None => false,
         };
         Ok(self.query.get_transaction(&tx_hash, verbose)?)
@@ -285,6 +622,10 @@ impl Connection {
     }
 
     fn handle_command(&mut self, method: &str, params: &[Value], id: &Value) -> Result<Value> {
+        self.stats
+            .requests
+            .with_label_values(&[self.transport])
+            .inc();
         let timer = self
             .stats
             .latency
@@ -298,8 +639,13 @@ impl Connection {
             "blockchain.relayfee" => self.blockchain_relayfee(),
             "blockchain.scripthash.get_balance" => self.blockchain_scripthash_get_balance(&params),
             "blockchain.scripthash.get_history" => self.blockchain_scripthash_get_history(&params),
+            "blockchain.scripthash.get_history_page" => {
+                self.blockchain_scripthash_get_history_page(&params)
+            }
+            "blockchain.scripthash.get_mempool" => self.blockchain_scripthash_get_mempool(&params),
             "blockchain.scripthash.listunspent" => self.blockchain_scripthash_listunspent(&params),
             "blockchain.scripthash.subscribe" => self.blockchain_scripthash_subscribe(&params),
+            "blockchain.scripthash.unsubscribe" => self.blockchain_scripthash_unsubscribe(&params),
             "blockchain.transaction.broadcast" => self.blockchain_transaction_broadcast(&params),
             "blockchain.transaction.get" => self.blockchain_transaction_get(&params),
             "blockchain.transaction.get_merkle" => self.blockchain_transaction_get_merkle(&params),
@@ -307,15 +653,16 @@ impl Connection {
                 self.blockchain_transaction_id_from_pos(&params)
             }
             "mempool.get_fee_histogram" => self.mempool_get_fee_histogram(),
+            "server.add_peer" => self.server_add_peer(&params),
             "server.banner" => self.server_banner(),
             "server.donation_address" => self.server_donation_address(),
+            "server.features" => self.server_features(),
             "server.peers.subscribe" => self.server_peers_subscribe(),
             "server.ping" => Ok(Value::Null),
-            "server.version" => self.server_version(),
-            &_ => bail!("unknown method {} {:?}", method, params),
+            "server.version" => self.server_version(&params),
+            &_ => Err(ErrorKind::MethodNotFound(method.to_owned()).into()),
         };
         timer.observe_duration();
-        // TODO: return application errors should be sent to the client
         Ok(match result {
             Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
             Err(e) => {
@@ -326,12 +673,13 @@ impl Connection {
                     params,
                     e.display_chain()
                 );
-                json!({"jsonrpc": "2.0", "id": id, "error": format!("{}", e)})
+                error_reply(id, &e)
             }
         })
     }
 
-    fn update_subscriptions(&mut self) -> Result<Vec<Value>> {
+    /// Re-evaluates the subscriptions affected by `changes` (or all of them, if `None`).
+    fn update_subscriptions(&mut self, changes: Option<&Changes>) -> Result<Vec<Value>> {
         let timer = self
             .stats
             .latency
@@ -350,181 +698,227 @@ impl Connection {
                     "params": [header]}));
             }
         }
-        for (script_hash, status_hash) in self.status_hashes.iter_mut() {
+        for (script_hash, subscription) in self.subscriptions.iter_mut() {
+            if let Some(changes) = changes {
+                if !changes.affects(&script_hash[..], &subscription.related_txids) {
+                    continue;
+                }
+            }
             let status = self.query.status(&script_hash[..])?;
-            let new_status_hash = status.hash().map_or(Value::Null, |h| json!(hex::encode(h)));
-            if new_status_hash == *status_hash {
-                continue;
+            let new_subscription = Subscription::new(&status);
+            let changed = new_subscription.status_hash != subscription.status_hash;
+            *subscription = new_subscription;
+            if changed {
+                result.push(json!({
+                    "jsonrpc": "2.0",
+                    "method": "blockchain.scripthash.subscribe",
+                    "params": [script_hash.be_hex_string(), subscription.status_hash]}));
             }
-            result.push(json!({
-                "jsonrpc": "2.0",
-                "method": "blockchain.scripthash.subscribe",
-                "params": [script_hash.be_hex_string(), new_status_hash]}));
-            *status_hash = new_status_hash;
         }
         timer.observe_duration();
-        self.stats
-            .subscriptions
-            .set(self.status_hashes.len() as i64);
         Ok(result)
     }
 
-    fn send_values(&mut self, values: &[Value]) -> Result<()> {
-        for value in values {
-            let line = value.to_string() + "\n";
-            self.stream
-                .write_all(line.as_bytes())
-                .chain_err(|| format!("failed to send {}", value))?;
+    /// Handles a JSON-RPC batch, returning `None` if it contains only notifications.
+    fn handle_batch(&mut self, cmds: &[Value]) -> Result<Option<Value>> {
+        if cmds.is_empty() {
+            let e = ErrorKind::InvalidRequest("empty batch".to_owned()).into();
+            return Ok(Some(error_reply(&Value::Null, &e)));
         }
-        Ok(())
-    }
-
-    fn handle_replies(&mut self) -> Result<()> {
-        let empty_params = json!([]);
-        loop {
-            let msg = self.chan.receiver().recv().chain_err(|| "channel closed")?;
-            trace!("RPC {:?}", msg);
-            match msg {
-                Message::Request(line) => {
-                    let cmd: Value = from_str(&line).chain_err(|| "invalid JSON format")?;
-                    let reply = match (
-                        cmd.get("method"),
-                        cmd.get("params").unwrap_or_else(|| &empty_params),
-                        cmd.get("id"),
-                    ) {
-                        (
-                            Some(&Value::String(ref method)),
-                            &Value::Array(ref params),
-                            Some(ref id),
-                        ) => self.handle_command(method, params, id)?,
-                        _ => bail!("invalid command: {}", cmd),
-                    };
-                    self.send_values(&[reply])?
-                }
-                Message::PeriodicUpdate => {
-                    let values = self
-                        .update_subscriptions()
-                        .chain_err(|| "failed to update subscriptions")?;
-                    self.send_values(&values)?
-                }
-                Message::Done => return Ok(()),
-            }
+        if self.settings.limits.batch > 0 && cmds.len() > self.settings.limits.batch {
+            let msg = format!(
+                "too many requests in batch ({} > {})",
+                cmds.len(),
+                self.settings.limits.batch
+            );
+            warn!("[{}] {}", self.addr, msg);
+            let e = ErrorKind::ResourceLimit(msg).into();
+            return Ok(Some(error_reply(&Value::Null, &e)));
         }
-    }
-
-    fn handle_requests(mut reader: BufReader<TcpStream>, tx: SyncSender<Message>) -> Result<()> {
-        loop {
-            let mut line = Vec::<u8>::new();
-            reader
-                .read_until(b'\n', &mut line)
-                .chain_err(|| "failed to read a request")?;
-            if line.is_empty() {
-                tx.send(Message::Done).chain_err(|| "channel closed")?;
-                return Ok(());
-            } else {
-                if line.starts_with(&[22, 3, 1]) {
-                    // (very) naive SSL handshake detection
-                    let _ = tx.send(Message::Done);
-                    bail!("invalid request - maybe SSL-encrypted data?: {:?}", line)
+        let timer = self
+            .stats
+            .latency
+            .with_label_values(&["batch"])
+            .start_timer();
+        let mut replies = vec![];
+        for cmd in cmds {
+            match parse_command(cmd) {
+                Some((method, params, Some(id))) => {
+                    replies.push(self.handle_command(method, params, id)?)
                 }
-                match String::from_utf8(line) {
-                    Ok(req) => tx
-                        .send(Message::Request(req))
-                        .chain_err(|| "channel closed")?,
-                    Err(err) => {
-                        let _ = tx.send(Message::Done);
-                        bail!("invalid UTF8: {}", err)
-                    }
+                Some((method, params, None)) => {
+                    self.handle_command(method, params, &Value::Null)?; // notification
+                }
+                None => {
+                    let e = ErrorKind::InvalidRequest(cmd.to_string()).into();
+                    replies.push(error_reply(&Value::Null, &e))
                 }
             }
         }
+        timer.observe_duration();
+        Ok(if replies.is_empty() {
+            None
+        } else {
+            Some(Value::Array(replies))
+        })
     }
 
-    pub fn run(mut self) {
-        let reader = BufReader::new(self.stream.try_clone().expect("failed to clone TcpStream"));
-        let tx = self.chan.sender();
-        let child = spawn_thread("reader", || Connection::handle_requests(reader, tx));
-        if let Err(e) = self.handle_replies() {
-            error!(
-                "[{}] connection handling failed: {}",
-                self.addr,
-                e.display_chain().to_string()
+    /// Handles a single request line, returning the reply (if any) and pending notifications.
+    fn handle_request(&mut self, line: &str) -> Result<Vec<Value>> {
+        let cmd: Value = from_str(line).chain_err(|| "invalid JSON format")?;
+        let reply = match cmd {
+            Value::Array(ref cmds) => self.handle_batch(cmds)?,
+            _ => match parse_command(&cmd) {
+                Some((method, params, Some(id))) => Some(self.handle_command(method, params, id)?),
+                _ => bail!("invalid command: {}", cmd),
+            },
+        };
+        let mut values: Vec<Value> = reply.into_iter().collect();
+        if self.update_pending {
+            self.update_pending = false;
+            values.extend(
+                self.update_subscriptions(None)
+                    .chain_err(|| "failed to update subscriptions")?,
             );
         }
-        debug!("[{}] shutting down connection", self.addr);
-        let _ = self.stream.shutdown(Shutdown::Both);
-        if let Err(err) = child.join().expect("receiver panicked") {
-            error!("[{}] receiver failed: {}", self.addr, err);
-        }
+        Ok(values)
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let subscriptions = self.subscriptions.len();
+        self.remove_subscriptions(subscriptions);
+        self.stats
+            .connections
+            .with_label_values(&[self.transport])
+            .dec();
     }
 }
 
 #[derive(Debug)]
 pub enum Message {
-    Request(String),
-    PeriodicUpdate,
+    Event(Event),
+    PeriodicUpdate(Arc<Changes>),
     Done,
 }
 
-pub enum Notification {
-    Periodic,
-    Exit,
-}
-
 pub struct RPC {
-    notification: Sender<Notification>,
-    server: Option<thread::JoinHandle<()>>, // so we can join the server while dropping this ojbect
+    server: transport::Handle,
+    event_loop: Option<thread::JoinHandle<()>>, // so we can join the server while dropping this ojbect
+    workers: Vec<(Sender<Message>, thread::JoinHandle<()>)>,
 }
 
 struct Stats {
     latency: HistogramVec,
     subscriptions: Gauge,
+    connections: GaugeVec,
+    requests: CounterVec,
+    server_subscriptions: AtomicUsize, // for enforcing the server-wide limit
+}
+
+fn encode(values: Vec<Value>) -> Vec<String> {
+    values.into_iter().map(|value| value.to_string()).collect()
 }
 
 impl RPC {
-    fn start_notifier(
-        notification: Channel<Notification>,
-        senders: Arc<Mutex<Vec<SyncSender<Message>>>>,
-        acceptor: Sender<Option<(TcpStream, SocketAddr)>>,
+    /// Handles the requests and notifications of a subset of the connections.
+    fn run_worker(
+        query: Arc<Query>,
+        settings: Arc<Settings>,
+        stats: Arc<Stats>,
+        server: transport::Handle,
+        messages: Receiver<Message>,
     ) {
-        spawn_thread("notification", move || {
-            for msg in notification.receiver().iter() {
-                let mut senders = senders.lock().unwrap();
-                match msg {
-                    Notification::Periodic => {
-                        for sender in senders.split_off(0) {
-                            if let Err(TrySendError::Disconnected(_)) =
-                                sender.try_send(Message::PeriodicUpdate)
-                            {
-                                continue;
+        let mut connections = HashMap::<usize, Connection>::new();
+        for msg in messages.iter() {
+            trace!("RPC {:?}", msg);
+            match msg {
+                Message::Event(Event::Open(id, addr, transport)) => {
+                    info!("[{}] connected peer", addr);
+                    let conn = Connection::new(
+                        query.clone(),
+                        addr,
+                        transport,
+                        settings.clone(),
+                        stats.clone(),
+                    );
+                    connections.insert(id, conn);
+                }
+                Message::Event(Event::Request(id, line)) => {
+                    let conn = match connections.get_mut(&id) {
+                        Some(conn) => conn,
+                        None => continue,
+                    };
+                    match conn.handle_request(&line) {
+                        Ok(values) => server.output(id, Output::Reply(encode(values))),
+                        Err(e) => {
+                            error!(
+                                "[{}] connection handling failed: {}",
+                                conn.addr,
+                                e.display_chain().to_string()
+                            );
+                            server.output(id, Output::Close);
+                        }
+                    }
+                }
+                Message::Event(Event::Close(id)) => {
+                    if let Some(conn) = connections.remove(&id) {
+                        info!("[{}] disconnected peer", conn.addr);
+                    }
+                }
+                Message::PeriodicUpdate(changes) => {
+                    for (id, conn) in connections.iter_mut() {
+                        match conn.update_subscriptions(Some(&changes)) {
+                            Ok(ref values) if values.is_empty() => (),
+                            Ok(values) => server.output(*id, Output::Notify(encode(values))),
+                            Err(e) => {
+                                error!(
+                                    "[{}] failed to update subscriptions: {}",
+                                    conn.addr,
+                                    e.display_chain().to_string()
+                                );
+                                server.output(*id, Output::Close);
                             }
-                            senders.push(sender);
                         }
                     }
-                    Notification::Exit => acceptor.send(None).unwrap(),
                 }
+                Message::Done => break,
             }
-        });
+        }
+        trace!("closing {} RPC connections", connections.len());
     }
 
-    fn start_acceptor(addr: SocketAddr) -> Channel<Option<(TcpStream, SocketAddr)>> {
-        let chan = Channel::new();
-        let acceptor = chan.sender();
-        spawn_thread("acceptor", move || {
-            let listener = TcpListener::bind(addr).expect(&format!("bind({}) failed", addr));
-            info!("RPC server running on {}", addr);
-            loop {
-                let (stream, addr) = listener.accept().expect("accept failed");
-                stream
-                    .set_nonblocking(false)
-                    .expect("failed to set connection as blocking");
-                acceptor.send(Some((stream, addr))).expect("send failed");
-            }
+    fn server_features(config: &Config, query: &Query) -> Result<Value> {
+        let ports = json!({
+            "tcp_port": config.electrum_rpc_addr.port(),
+            "ssl_port": config.electrum_ssl_addr.map(|addr| addr.port()),
         });
-        chan
+        let hosts: serde_json::Map<String, Value> = config
+            .electrum_public_hosts
+            .iter()
+            .map(|host| (host.clone(), ports.clone()))
+            .collect();
+        let genesis_hash = query
+            .get_genesis_hash()
+            .chain_err(|| "server not ready: no indexed headers")?;
+        Ok(json!({
+            "genesis_hash": genesis_hash.be_hex_string(),
+            "hosts": hosts,
+            "protocol_min": PROTOCOL_VERSION_MIN.to_string(),
+            "protocol_max": PROTOCOL_VERSION_MAX.to_string(),
+            "hash_function": "sha256",
+            "server_version": SERVER_VERSION,
+            "pruning": Value::Null,
+        }))
     }
 
-    pub fn start(addr: SocketAddr, query: Arc<Query>, metrics: &Metrics) -> RPC {
+    pub fn start(
+        config: &Config,
+        tls: Option<TlsAcceptor>,
+        query: Arc<Query>,
+        metrics: &Metrics,
+    ) -> Result<RPC> {
         let stats = Arc::new(Stats {
             latency: metrics.histogram_vec(
                 HistogramOpts::new("electrum_rpc", "Electrum RPC latency (seconds)"),
@@ -534,51 +928,198 @@ impl RPC {
                 "electrum_subscriptions",
                 "# of Electrum subscriptions",
             )),
+            connections: metrics.gauge_vec(
+                MetricOpts::new("electrum_connections", "# of connected Electrum clients"),
+                &["transport"],
+            ),
+            requests: metrics.counter_vec(
+                MetricOpts::new("electrum_requests", "# of Electrum RPC requests"),
+                &["transport"],
+            ),
+            server_subscriptions: AtomicUsize::new(0),
         });
-        let notification = Channel::new();
-        let handle = RPC {
-            notification: notification.sender(),
-            server: Some(spawn_thread("rpc", move || {
-                let senders = Arc::new(Mutex::new(Vec::<SyncSender<Message>>::new()));
-                let acceptor = RPC::start_acceptor(addr);
-                RPC::start_notifier(notification, senders.clone(), acceptor.sender());
-                let mut children = vec![];
-                while let Some((stream, addr)) = acceptor.receiver().recv().unwrap() {
-                    let query = query.clone();
-                    let senders = senders.clone();
-                    let stats = stats.clone();
-                    children.push(spawn_thread("peer", move || {
-                        info!("[{}] connected peer", addr);
-                        let conn = Connection::new(query, stream, addr, stats);
-                        senders.lock().unwrap().push(conn.chan.sender());
-                        conn.run();
-                        info!("[{}] disconnected peer", addr);
-                    }));
-                }
-                trace!("closing {} RPC connections", senders.lock().unwrap().len());
-                for sender in senders.lock().unwrap().iter() {
-                    let _ = sender.send(Message::Done);
-                }
-                trace!("waiting for {} RPC handling threads", children.len());
-                for child in children {
-                    let _ = child.join();
-                }
-                trace!("RPC connections are closed");
+        let addr = config.electrum_rpc_addr;
+        let tls = config
+            .electrum_ssl_addr
+            .and_then(|addr| tls.map(|acceptor| (addr, Arc::new(acceptor))));
+        let ws_addr = config.electrum_ws_addr;
+        let features = RPC::server_features(config, &query)?;
+        let settings = Arc::new(Settings {
+            limits: Limits {
+                batch: config.batch_limit,
+                connection_subscriptions: config.connection_subscription_limit,
+                server_subscriptions: config.server_subscription_limit,
+            },
+            features: features.clone(),
+            peers: if config.electrum_peer_discovery {
+                let seeds = config
+                    .electrum_peers
+                    .iter()
+                    .map(|(host, port)| PeerAddr {
+                        host: host.clone(),
+                        tcp_port: Some(*port),
+                        ssl_port: None,
+                    })
+                    .collect();
+                let path = config.db_path.join("peers.json");
+                Some(Peers::start(&path, seeds, features))
+            } else {
+                None
+            },
+        });
+        let mut listeners = vec![(addr, Transport::Tcp)];
+        if let Some((addr, tls)) = tls {
+            listeners.push((addr, Transport::Tls(tls)));
+        }
+        if let Some(addr) = ws_addr {
+            listeners.push((addr, Transport::WebSocket));
+        }
+        let (event_loop, server) =
+            EventLoop::new(listeners).chain_err(|| "failed to start RPC server")?;
+        let workers: Vec<(Sender<Message>, thread::JoinHandle<()>)> = (0..config.rpc_threads)
+            .map(|_| {
+                let messages = Channel::new();
+                let sender = messages.sender();
+                let query = query.clone();
+                let settings = settings.clone();
+                let stats = stats.clone();
+                let server = server.clone();
+                let handle = spawn_thread("rpc_worker", move || {
+                    RPC::run_worker(query, settings, stats, server, messages.into_receiver())
+                });
+                (sender, handle)
+            })
+            .collect();
+        // each connection is handled by a single worker, so its requests are processed in order
+        let senders: Vec<Sender<Message>> = workers.iter().map(|(tx, _)| tx.clone()).collect();
+        Ok(RPC {
+            server,
+            event_loop: Some(spawn_thread("rpc", move || {
+                event_loop.run(|event| {
+                    let id = match event {
+                        Event::Open(id, ..) | Event::Request(id, _) | Event::Close(id) => id,
+                    };
+                    let _ = senders[id % senders.len()].send(Message::Event(event));
+                })
             })),
-        };
-        handle
+            workers,
+        })
     }
 
-    pub fn notify(&self) {
-        self.notification.send(Notification::Periodic).unwrap();
+    /// Notifies the clients whose subscriptions are affected by `changes`.
+    pub fn notify(&self, changes: Changes) {
+        if changes.is_empty() {
+            return;
+        }
+        let changes = Arc::new(changes);
+        for (sender, _) in &self.workers {
+            sender
+                .send(Message::PeriodicUpdate(changes.clone()))
+                .unwrap();
+        }
     }
 }
 
 impl Drop for RPC {
     fn drop(&mut self) {
         trace!("stop accepting new RPCs");
-        self.notification.send(Notification::Exit).unwrap();
-        self.server.take().map(|t| t.join().unwrap());
+        self.server.exit();
+        self.event_loop.take().map(|t| t.join().unwrap());
+        for (sender, _) in &self.workers {
+            let _ = sender.send(Message::Done);
+        }
+        trace!("waiting for {} RPC handling threads", self.workers.len());
+        for (_, worker) in self.workers.drain(..) {
+            let _ = worker.join();
+        }
         trace!("RPC server is stopped");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(s: &str) -> ProtocolVersion {
+        ProtocolVersion::parse(s).unwrap()
+    }
+
+    #[test]
+    fn test_negotiate_version() {
+        assert_eq!(version("1.4.2"), ProtocolVersion::new(1, 4, 2));
+        assert_eq!(version("1.4").to_string(), "1.4");
+        assert!(ProtocolVersion::parse("1").is_err());
+        assert!(ProtocolVersion::parse("1.x").is_err());
+
+        let negotiated = negotiate_version(version("1.2"), version("1.9")).unwrap();
+        assert_eq!(negotiated, PROTOCOL_VERSION_MAX);
+        let negotiated = negotiate_version(version("1.4"), version("1.4")).unwrap();
+        assert_eq!(negotiated, version("1.4"));
+        assert!(negotiate_version(version("1.2"), version("1.3")).is_err());
+        assert!(negotiate_version(version("1.5"), version("1.6")).is_err());
+    }
+
+    #[test]
+    fn test_error_value() {
+        let e: Error = ErrorKind::Daemon(-26, "txn-mempool-conflict".to_owned()).into();
+        let e = rejection(
+            Err::<(), _>(e)
+                .chain_err(|| "sendrawtransaction RPC failed")
+                .unwrap_err(),
+        );
+        assert_eq!(
+            error_value(&e),
+            json!({"code": -26, "message": "txn-mempool-conflict"})
+        );
+
+        let e: Error = ErrorKind::Daemon(-5, "No such mempool transaction".to_owned()).into();
+        let e = Err::<(), _>(e)
+            .chain_err(|| "getrawtransaction RPC failed")
+            .unwrap_err();
+        assert_eq!(
+            error_value(&e),
+            json!({"code": DAEMON_ERROR, "message": "daemon error -5: No such mempool transaction"})
+        );
+        let e: Error = ErrorKind::Daemon(METHOD_NOT_FOUND, "Method not found".to_owned()).into();
+        assert_eq!(error_value(&e)["code"], DAEMON_ERROR);
+        assert_eq!(
+            error_value(&rejection("failed to connect".into()))["code"],
+            INTERNAL_ERROR
+        );
+
+        let e = hash_from_value(Some(&json!("xyz"))).chain_err(|| "bad script_hash");
+        assert_eq!(
+            error_value(&e.unwrap_err()),
+            json!({"code": BAD_REQUEST, "message": "bad script_hash"})
+        );
+
+        let e: Error = "failed to read".into();
+        assert_eq!(error_value(&e)["code"], INTERNAL_ERROR);
+    }
+
+    #[test]
+    fn test_u32_from_value() {
+        assert_eq!(u32_from_value_or(None, "height", 7).unwrap(), 7);
+        let max = json!(u32::MAX);
+        assert_eq!(
+            u32_from_value_or(Some(&max), "height", 0).unwrap(),
+            u32::MAX
+        );
+        let err = u32_from_value_or(Some(&json!(1u64 << 32)), "height", 0).unwrap_err();
+        assert_eq!(error_value(&err)["code"], BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_history_cursor() {
+        let cursor = cursor_to_value(Some(b"\x01\x23\xab".to_vec()));
+        assert_eq!(cursor, json!("0123ab"));
+        assert_eq!(
+            cursor_from_value(Some(&cursor)).unwrap(),
+            Some(b"\x01\x23\xab".to_vec())
+        );
+        assert_eq!(cursor_from_value(Some(&Value::Null)).unwrap(), None);
+        assert_eq!(cursor_to_value(None), Value::Null);
+        assert!(cursor_from_value(Some(&json!("123"))).is_err());
+        assert!(cursor_from_value(Some(&json!(123))).is_err());
+    }
+}
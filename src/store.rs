@@ -1,7 +1,136 @@
+use bincode;
+use hex;
 use rocksdb;
+use std::collections::HashMap;
+use std::mem;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use crate::util::Bytes;
+use crate::errors::*;
+use crate::metrics::{MetricOpts, Metrics};
+use crate::util::{spawn_thread, Bytes, HASH_PREFIX_LEN};
+
+/// The version of the DB schema (see doc/schema.md): it should be bumped on any change in the
+/// rows' layout, and a matching migration should be added to `MIGRATIONS` below.
+pub const DB_VERSION: u32 = 4;
+
+/// Each row type is stored at its own column family, having its own options.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Family {
+    Default, // markers and pointers (e.g. `b"L"`)
+    TxOut,   // `b'O'` rows
+    TxIn,    // `b'I'` rows
+    Tx,      // `b'T'` rows
+    Block,   // `b'B'` rows
+    Utxo,    // `b'U'` rows (and their `b'P'` and `b'S'` rows)
+    Undo,    // `b'D'` rows
+    RawTx,   // `b'R'` rows
+}
+
+/// The key prefix (code + hash prefix) of the prefix-scanned rows, used for prefix bloom filters.
+const PREFIX_LEN: usize = 1 + HASH_PREFIX_LEN;
+
+const FAMILIES: &[Family] = &[
+    Family::Default,
+    Family::TxOut,
+    Family::TxIn,
+    Family::Tx,
+    Family::Block,
+    Family::Utxo,
+    Family::Undo,
+    Family::RawTx,
+];
+
+impl Family {
+    /// Returns the family of a row, by its key's code.
+    pub fn of(key: &[u8]) -> Family {
+        match key.first() {
+            Some(b'O') => Family::TxOut,
+            Some(b'I') => Family::TxIn,
+            Some(b'T') => Family::Tx,
+            Some(b'B') => Family::Block,
+            Some(b'U') | Some(b'P') | Some(b'S') => Family::Utxo,
+            Some(b'D') => Family::Undo,
+            Some(b'R') => Family::RawTx,
+            _ => Family::Default,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Family::Default => "default",
+            Family::TxOut => "txout",
+            Family::TxIn => "txin",
+            Family::Tx => "tx",
+            Family::Block => "block",
+            Family::Utxo => "utxo",
+            Family::Undo => "undo",
+            Family::RawTx => "rawtx",
+        }
+    }
+
+    /// Returns the length of the prefixes that the family's rows are scanned by (if any).
+    /// Such scans (and lookups) are served by prefix bloom filters, so shorter prefixes
+    /// must be scanned in total order (see `DBStore::scan`).
+    fn prefix_len(self) -> Option<usize> {
+        match self {
+            Family::TxOut | Family::TxIn | Family::Tx | Family::Utxo => Some(PREFIX_LEN),
+            Family::Default | Family::Block | Family::Undo | Family::RawTx => None,
+        }
+    }
+
+    /// `block_opts` are shared by all the families (e.g. their LRU block cache and bloom
+    /// filter policy), and are copied into the returned options.
+    fn options(
+        self,
+        opts: &Options,
+        block_opts: &mut rocksdb::BlockBasedOptions,
+    ) -> rocksdb::Options {
+        let mut cf_opts = rocksdb::Options::default();
+        cf_opts.set_compaction_style(rocksdb::DBCompactionStyle::Level);
+        cf_opts.set_target_file_size_base(256 << 20);
+        cf_opts.set_disable_auto_compactions(opts.bulk_import); // for initial bulk load
+        match self {
+            // prefix-scanned rows, whose keys are (mostly) hashes - so they don't compress well
+            Family::TxOut | Family::TxIn => {
+                cf_opts.set_compression_type(rocksdb::DBCompressionType::None);
+                cf_opts.set_write_buffer_size(256 << 20);
+                block_opts.set_block_size(if opts.low_memory { 256 << 10 } else { 1 << 20 });
+            }
+            // looked up by txid (prefix), so smaller blocks are read for each lookup
+            Family::Tx => {
+                cf_opts.set_compression_type(rocksdb::DBCompressionType::None);
+                cf_opts.set_write_buffer_size(256 << 20);
+                block_opts.set_block_size(16 << 10);
+            }
+            Family::Utxo => {
+                cf_opts.set_compression_type(rocksdb::DBCompressionType::Snappy);
+                cf_opts.set_write_buffer_size(256 << 20);
+                block_opts.set_block_size(if opts.low_memory { 256 << 10 } else { 1 << 20 });
+            }
+            // looked up by full txid (using a whole key bloom filter)
+            Family::RawTx => {
+                cf_opts.set_compression_type(rocksdb::DBCompressionType::Snappy);
+                cf_opts.set_write_buffer_size(256 << 20);
+                block_opts.set_block_size(16 << 10);
+            }
+            // small families, which are read (almost) only on startup and on reorgs
+            Family::Default | Family::Block | Family::Undo => {
+                cf_opts.set_compression_type(rocksdb::DBCompressionType::Snappy);
+                cf_opts.set_write_buffer_size(16 << 20);
+                block_opts.set_block_size(4 << 10);
+            }
+        }
+        if let Some(len) = self.prefix_len() {
+            // most lookups are of missing prefixes (e.g. unused addresses)
+            cf_opts.set_prefix_extractor(rocksdb::SliceTransform::create_fixed_prefix(len));
+        }
+        cf_opts.set_block_based_table_factory(block_opts);
+        cf_opts
+    }
+}
 
 #[derive(Clone)]
 pub struct Row {
@@ -13,15 +142,28 @@ impl Row {
     pub fn into_pair(self) -> (Bytes, Bytes) {
         (self.key, self.value)
     }
+
+    pub fn family(&self) -> Family {
+        Family::of(&self.key)
+    }
 }
 
+/// Iterates lazily over the rows of a scan (in key order), so it may be stopped early.
+pub type RowIterator<'a> = Box<Iterator<Item = Row> + 'a>;
+
 pub trait ReadStore: Sync {
-    fn get(&self, key: &[u8]) -> Option<Bytes>;
-    fn scan(&self, prefix: &[u8]) -> Vec<Row>;
+    fn get(&self, family: Family, key: &[u8]) -> Option<Bytes>;
+    fn scan<'a>(&'a self, family: Family, prefix: &[u8]) -> RowIterator<'a>;
+    /// Scans the rows starting with `prefix`, from the first key not smaller than `start`
+    /// (which must begin with `prefix`), e.g. for resuming an earlier scan.
+    fn scan_from<'a>(&'a self, family: Family, prefix: &[u8], start: &[u8]) -> RowIterator<'a>;
 }
 
+/// Each row is written to (or deleted from) its key's family (see `Family::of`).
 pub trait WriteStore: Sync {
     fn write(&self, rows: Vec<Row>);
+    /// Deletes the rows of the specified keys and writes the new rows (atomically).
+    fn delete_and_write(&self, keys: Vec<Bytes>, rows: Vec<Row>);
     fn flush(&self);
 }
 
@@ -30,80 +172,313 @@ struct Options {
     path: PathBuf,
     bulk_import: bool,
     low_memory: bool,
+    cache_size: usize,
 }
 
 pub struct DBStore {
-    db: rocksdb::DB,
+    db: Arc<rocksdb::DB>,
+    db_opts: Arc<Mutex<rocksdb::Options>>, // for exporting the DB statistics
     opts: Options,
 }
 
 impl DBStore {
-    fn open_opts(opts: Options) -> Self {
+    fn open_opts(opts: Options) -> Result<Self> {
         debug!("opening DB at {:?}", opts.path);
         let mut db_opts = rocksdb::Options::default();
         db_opts.create_if_missing(true);
+        db_opts.create_missing_column_families(true);
         // db_opts.set_keep_log_file_num(10);
         db_opts.set_max_open_files(if opts.bulk_import { 16 } else { 256 });
-        db_opts.set_compaction_style(rocksdb::DBCompactionStyle::Level);
-        db_opts.set_compression_type(rocksdb::DBCompressionType::Snappy);
-        db_opts.set_target_file_size_base(256 << 20);
-        db_opts.set_write_buffer_size(256 << 20);
-        db_opts.set_disable_auto_compactions(opts.bulk_import); // for initial bulk load
         db_opts.set_advise_random_on_open(!opts.bulk_import); // bulk load uses sequential I/O
         if opts.low_memory == false {
             db_opts.set_compaction_readahead_size(1 << 20);
         }
+        db_opts.enable_statistics();
 
         let mut block_opts = rocksdb::BlockBasedOptions::default();
-        block_opts.set_block_size(if opts.low_memory { 256 << 10 } else { 1 << 20 });
-        DBStore {
-            db: rocksdb::DB::open(&db_opts, &opts.path).unwrap(),
-            opts,
+        block_opts.set_lru_cache(opts.cache_size); // a single cache, shared by all the families
+        block_opts.set_bloom_filter(10, false);
+        let mut families: Vec<rocksdb::ColumnFamilyDescriptor> = FAMILIES
+            .iter()
+            .map(|family| {
+                let cf_opts = family.options(&opts, &mut block_opts);
+                rocksdb::ColumnFamilyDescriptor::new(family.name(), cf_opts)
+            })
+            .collect();
+        // all the existing families must be opened (the version check refuses unknown ones)
+        let existing = rocksdb::DB::list_cf(&db_opts, &opts.path).unwrap_or_default();
+        for name in existing {
+            if !FAMILIES.iter().any(|family| family.name() == name) {
+                warn!("unknown column family {:?}", name);
+                families.push(rocksdb::ColumnFamilyDescriptor::new(
+                    name,
+                    rocksdb::Options::default(),
+                ));
+            }
         }
+        let db = rocksdb::DB::open_cf_descriptors(&db_opts, &opts.path, families)
+            .map_err(|e| format!("failed to open DB at {:?}: {}", opts.path, e))?;
+        Ok(DBStore {
+            db: Arc::new(db),
+            db_opts: Arc::new(Mutex::new(db_opts)),
+            opts,
+        })
     }
 
-    /// Opens a new RocksDB at the specified location.
-    pub fn open(path: &Path, low_memory: bool) -> Self {
-        DBStore::open_opts(Options {
+    fn cf(&self, family: Family) -> rocksdb::ColumnFamily {
+        cf_handle(&self.db, family)
+    }
+
+    /// Opens a RocksDB at the specified location, creating or migrating its schema if needed.
+    /// `cache_size` is the size (in bytes) of the block cache, shared by all the families.
+    pub fn open(path: &Path, low_memory: bool, cache_size: usize) -> Result<Self> {
+        let store = DBStore::open_opts(Options {
             path: path.to_path_buf(),
             bulk_import: true,
             low_memory,
+            cache_size,
+        })?;
+        store.check_version()?;
+        Ok(store)
+    }
+
+    fn is_empty(&self) -> bool {
+        FAMILIES.iter().all(|family| {
+            let mode = rocksdb::IteratorMode::Start;
+            self.db
+                .full_iterator_cf(self.cf(*family), mode)
+                .unwrap()
+                .next()
+                .is_none()
         })
     }
 
+    fn check_version(&self) -> Result<()> {
+        let mut version = match self.get(Family::Default, &version_key()) {
+            Some(value) => bincode::deserialize(&value).chain_err(|| "invalid DB version")?,
+            None if self.is_empty() => {
+                debug!("creating DB schema version {}", DB_VERSION);
+                self.write(vec![version_row(DB_VERSION)]);
+                self.flush();
+                return Ok(());
+            }
+            None => 0, // created before schema versioning
+        };
+        if version > DB_VERSION {
+            bail!(
+                "DB at {:?} has schema version {}, which is newer than the supported one ({}): \
+                 please upgrade electrs, or remove the DB to reindex",
+                self.opts.path,
+                version,
+                DB_VERSION
+            );
+        }
+        while version < DB_VERSION {
+            let migration = match MIGRATIONS.iter().find(|m| m.from_version == version) {
+                Some(migration) => migration,
+                None => bail!(
+                    "DB at {:?} has schema version {}, which cannot be migrated to {}: \
+                     please remove the DB to reindex",
+                    self.opts.path,
+                    version,
+                    DB_VERSION
+                ),
+            };
+            info!(
+                "migrating DB schema from version {} to {}: {}",
+                version,
+                version + 1,
+                migration.description
+            );
+            (migration.run)(self, &mut MigrationProgress::new(version + 1))
+                .chain_err(|| format!("DB migration to version {} failed", version + 1))?;
+            version += 1;
+            self.write(vec![version_row(version)]);
+            self.flush();
+            info!("migrated DB schema to version {}", version);
+        }
+        Ok(())
+    }
+
+    /// Reopens the DB (if needed), since auto-compactions are disabled during initial bulk load.
     pub fn enable_compaction(self) -> Self {
-        let mut opts = self.opts.clone();
-        if opts.bulk_import == true {
-            opts.bulk_import = false;
-            info!("enabling auto-compactions");
-            let opts = [("disable_auto_compactions", "false")];
-            self.db.set_options(&opts).unwrap();
+        if !self.opts.bulk_import {
+            return self;
         }
-        self
+        info!("enabling auto-compactions");
+        let mut opts = self.opts.clone();
+        opts.bulk_import = false;
+        drop(self); // no snapshots may be left, so the DB is closed before reopening it
+        DBStore::open_opts(opts).expect("failed to reopen DB")
     }
 
     pub fn compact(self) -> Self {
         info!("starting full compaction");
-        self.db.compact_range(None, None); // would take a while
+        for family in FAMILIES {
+            self.db.compact_range_cf(self.cf(*family), None, None); // would take a while
+        }
         info!("finished full compaction");
         self
     }
 
-    pub fn iter_scan(&self, prefix: &[u8]) -> ScanIterator {
-        ScanIterator {
-            prefix: prefix.to_vec(),
-            iter: self.db.prefix_iterator(prefix),
-            done: false,
+    /// Returns a consistent view of the DB, which is not affected by later writes.
+    pub fn snapshot(&self) -> SnapshotStore {
+        let snapshot = self.db.snapshot();
+        // the snapshot refers to the DB, which is kept alive by `SnapshotStore::db` below
+        let snapshot =
+            unsafe { mem::transmute::<rocksdb::Snapshot, rocksdb::Snapshot<'static>>(snapshot) };
+        SnapshotStore {
+            snapshot,
+            db: Arc::clone(&self.db),
+        }
+    }
+
+    // Prefix seek is used only when the family's prefix extractor covers the scanned prefix
+    // (so `start` has the same extracted prefix).
+    fn iterator(&self, family: Family, prefix: &[u8], start: &[u8]) -> rocksdb::DBIterator {
+        let cf = self.cf(family);
+        if is_prefix_seek(family, prefix) {
+            self.db.prefix_iterator_cf(cf, start).unwrap()
+        } else {
+            let mode = rocksdb::IteratorMode::From(start, rocksdb::Direction::Forward);
+            self.db.full_iterator_cf(cf, mode).unwrap()
+        }
+    }
+
+    /// Exports the DB statistics (e.g. block cache hit rates), until the process exits.
+    pub fn start_stats_exporter(&self, metrics: &Metrics) {
+        let stats = metrics.gauge_vec(
+            MetricOpts::new("db_stats", "RocksDB statistics (since the DB was opened)"),
+            &["type"],
+        );
+        let hit_ratio = metrics.gauge_vec(
+            MetricOpts::new("db_cache_hit_ratio", "RocksDB block cache hit ratio"),
+            &["type"],
+        );
+        let db_opts = Arc::clone(&self.db_opts);
+        spawn_thread("db_stats", move || loop {
+            let values = match db_opts.lock().unwrap().get_statistics() {
+                Some(text) => parse_db_stats(&text),
+                None => {
+                    warn!("DB statistics are disabled");
+                    break;
+                }
+            };
+            let value = |name: &str| values.get(name).cloned().unwrap_or(0);
+            for name in EXPORTED_STATS {
+                stats.with_label_values(&[name]).set(value(name) as f64);
+            }
+            for (label, prefix) in CACHE_STATS {
+                let hits = value(&format!("{}.hit", prefix));
+                let misses = value(&format!("{}.miss", prefix));
+                if hits + misses > 0 {
+                    let ratio = hits as f64 / (hits + misses) as f64;
+                    hit_ratio.with_label_values(&[label]).set(ratio);
+                }
+            }
+            thread::sleep(Duration::from_secs(5));
+        });
+    }
+
+    // Moves the rows of a family from the single keyspace of schema version 1.
+    fn move_to_family(&self, family: Family, progress: &mut MigrationProgress) {
+        let code = match family {
+            Family::Default | Family::RawTx => return,
+            Family::TxOut => b"O",
+            Family::TxIn => b"I",
+            Family::Tx => b"T",
+            Family::Block => b"B",
+            Family::Utxo => b"U",
+            Family::Undo => b"D",
+        };
+        let default_cf = self.cf(Family::Default);
+        let mut batch = rocksdb::WriteBatch::default();
+        let mode = rocksdb::IteratorMode::From(code, rocksdb::Direction::Forward);
+        for (key, value) in self.db.iterator_cf(default_cf, mode).unwrap() {
+            if !key.starts_with(code) {
+                break;
+            }
+            batch.put_cf(self.cf(family), &key, &value).unwrap();
+            batch.delete_cf(default_cf, &key).unwrap();
+            progress.inc();
+            if batch.len() >= 200_000 {
+                // using WAL, since the families may be flushed separately
+                self.db.write(batch).unwrap();
+                batch = rocksdb::WriteBatch::default();
+            }
         }
+        self.db.write(batch).unwrap();
     }
 }
 
-pub struct ScanIterator {
+fn is_prefix_seek(family: Family, prefix: &[u8]) -> bool {
+    match family.prefix_len() {
+        Some(len) => prefix.len() >= len,
+        None => false,
+    }
+}
+
+const EXPORTED_STATS: &[&str] = &[
+    "block.cache.hit",
+    "block.cache.miss",
+    "block.cache.data.hit",
+    "block.cache.data.miss",
+    "block.cache.index.hit",
+    "block.cache.index.miss",
+    "block.cache.filter.hit",
+    "block.cache.filter.miss",
+    "block.cache.add",
+    "bloom.filter.useful",
+    "bloom.filter.prefix.checked",
+    "bloom.filter.prefix.useful",
+];
+
+const CACHE_STATS: &[(&str, &str)] = &[
+    ("all", "block.cache"),
+    ("data", "block.cache.data"),
+    ("index", "block.cache.index"),
+    ("filter", "block.cache.filter"),
+];
+
+// Parses the tickers of `rocksdb::Options::get_statistics()`, e.g. `rocksdb.block.cache.hit COUNT : 7`
+// (histograms, having other fields, are skipped).
+fn parse_db_stats(text: &str) -> HashMap<String, u64> {
+    text.lines()
+        .filter_map(|line| {
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            match parts.as_slice() {
+                [name, "COUNT", ":", count] => Some((
+                    name.trim_start_matches("rocksdb.").to_owned(),
+                    count.parse().ok()?,
+                )),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+fn cf_handle(db: &rocksdb::DB, family: Family) -> rocksdb::ColumnFamily {
+    db.cf_handle(family.name())
+        .unwrap_or_else(|| panic!("missing {:?} column family", family))
+}
+
+// Returns the rows starting with `prefix`, stopping at the first row that doesn't.
+struct ScanIterator {
     prefix: Vec<u8>,
     iter: rocksdb::DBIterator,
     done: bool,
 }
 
+impl ScanIterator {
+    fn new(iter: rocksdb::DBIterator, prefix: &[u8]) -> Self {
+        ScanIterator {
+            prefix: prefix.to_vec(),
+            iter,
+            done: false,
+        }
+    }
+}
+
 impl Iterator for ScanIterator {
     type Item = Row;
 
@@ -124,34 +499,75 @@ impl Iterator for ScanIterator {
 }
 
 impl ReadStore for DBStore {
-    fn get(&self, key: &[u8]) -> Option<Bytes> {
-        self.db.get(key).unwrap().map(|v| v.to_vec())
+    fn get(&self, family: Family, key: &[u8]) -> Option<Bytes> {
+        self.db
+            .get_cf(self.cf(family), key)
+            .unwrap()
+            .map(|v| v.to_vec())
+    }
+
+    fn scan<'a>(&'a self, family: Family, prefix: &[u8]) -> RowIterator<'a> {
+        self.scan_from(family, prefix, prefix)
     }
 
-    // TODO: use generators
-    fn scan(&self, prefix: &[u8]) -> Vec<Row> {
-        let mut rows = vec![];
-        for (key, value) in self.db.iterator(rocksdb::IteratorMode::From(
+    fn scan_from<'a>(&'a self, family: Family, prefix: &[u8], start: &[u8]) -> RowIterator<'a> {
+        Box::new(ScanIterator::new(
+            self.iterator(family, prefix, start),
             prefix,
-            rocksdb::Direction::Forward,
-        )) {
-            if !key.starts_with(prefix) {
-                break;
-            }
-            rows.push(Row {
-                key: key.to_vec(),
-                value: value.to_vec(),
-            });
-        }
-        rows
+        ))
+    }
+}
+
+pub struct SnapshotStore {
+    snapshot: rocksdb::Snapshot<'static>, // must be dropped before the DB
+    db: Arc<rocksdb::DB>,
+}
+
+// RocksDB snapshots are immutable, so they can be read concurrently.
+unsafe impl Send for SnapshotStore {}
+unsafe impl Sync for SnapshotStore {}
+
+impl ReadStore for SnapshotStore {
+    fn get(&self, family: Family, key: &[u8]) -> Option<Bytes> {
+        let cf = cf_handle(&self.db, family);
+        self.snapshot.get_cf(cf, key).unwrap().map(|v| v.to_vec())
+    }
+
+    fn scan<'a>(&'a self, family: Family, prefix: &[u8]) -> RowIterator<'a> {
+        self.scan_from(family, prefix, prefix)
+    }
+
+    fn scan_from<'a>(&'a self, family: Family, prefix: &[u8], start: &[u8]) -> RowIterator<'a> {
+        // snapshot iterators can't use total order seek
+        assert!(
+            family.prefix_len().is_none() || is_prefix_seek(family, prefix),
+            "too short {:?} prefix: {}",
+            family,
+            hex::encode(prefix)
+        );
+        let cf = cf_handle(&self.db, family);
+        let mode = rocksdb::IteratorMode::From(start, rocksdb::Direction::Forward);
+        let iter = self.snapshot.iterator_cf(cf, mode).unwrap(); // must not outlive the snapshot
+        Box::new(ScanIterator::new(iter, prefix))
     }
 }
 
 impl WriteStore for DBStore {
     fn write(&self, rows: Vec<Row>) {
+        self.delete_and_write(vec![], rows)
+    }
+
+    fn delete_and_write(&self, keys: Vec<Bytes>, rows: Vec<Row>) {
         let mut batch = rocksdb::WriteBatch::default();
+        for key in keys {
+            let cf = self.cf(Family::of(&key));
+            batch.delete_cf(cf, key.as_slice()).unwrap();
+        }
         for row in rows {
-            batch.put(row.key.as_slice(), row.value.as_slice()).unwrap();
+            let cf = self.cf(row.family());
+            batch
+                .put_cf(cf, row.key.as_slice(), row.value.as_slice())
+                .unwrap();
         }
         let mut opts = rocksdb::WriteOptions::new();
         opts.set_sync(!self.opts.bulk_import);
@@ -174,6 +590,117 @@ impl Drop for DBStore {
     }
 }
 
+fn version_key() -> Bytes {
+    b"V".to_vec()
+}
+
+fn version_row(version: u32) -> Row {
+    Row {
+        key: version_key(),
+        value: bincode::serialize(&version).unwrap(),
+    }
+}
+
+/// Logs the progress of a long-running migration.
+pub struct MigrationProgress {
+    version: u32,
+    rows: usize,
+    last_report: Instant,
+}
+
+impl MigrationProgress {
+    fn new(version: u32) -> Self {
+        MigrationProgress {
+            version,
+            rows: 0,
+            last_report: Instant::now(),
+        }
+    }
+
+    /// Should be called for each migrated row.
+    pub fn inc(&mut self) {
+        self.rows += 1;
+        if self.last_report.elapsed() > Duration::from_secs(10) {
+            info!(
+                "migrating DB schema to version {}: {} rows done",
+                self.version, self.rows
+            );
+            self.last_report = Instant::now();
+        }
+    }
+}
+
+struct Migration {
+    from_version: u32, // upgrades the DB to `from_version + 1`
+    description: &'static str,
+    // may be interrupted, so it must be safe to run again on a partially migrated DB
+    run: fn(&DBStore, &mut MigrationProgress) -> Result<()>,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        from_version: 0,
+        description: "add schema version row",
+        run: migrate_unversioned,
+    },
+    Migration {
+        from_version: 1,
+        description: "move each row type to its own column family",
+        run: migrate_to_families,
+    },
+    Migration {
+        from_version: 2,
+        description: "add raw transactions' column family",
+        run: migrate_raw_txs,
+    },
+    Migration {
+        from_version: 3,
+        description: "delete the UTXO index and the undo data, having a new layout",
+        run: migrate_spent_utxos,
+    },
+];
+
+fn migrate_unversioned(_store: &DBStore, _progress: &mut MigrationProgress) -> Result<()> {
+    // the layout is unchanged, but undo data exists only for blocks indexed after the upgrade
+    info!("orphaned blocks indexed before the upgrade will be fetched from bitcoind on reorg");
+    Ok(())
+}
+
+fn migrate_to_families(store: &DBStore, progress: &mut MigrationProgress) -> Result<()> {
+    for family in FAMILIES {
+        store.move_to_family(*family, progress);
+    }
+    Ok(())
+}
+
+fn migrate_raw_txs(_store: &DBStore, _progress: &mut MigrationProgress) -> Result<()> {
+    // the family is created on open, and is filled only by DBs indexed with `--store-txs`
+    Ok(())
+}
+
+// The UTXO rows of the spent outputs are now deleted, which requires re-indexing all the blocks
+// (and the undo data now has the UTXO rows spent by each block).
+fn migrate_spent_utxos(store: &DBStore, progress: &mut MigrationProgress) -> Result<()> {
+    let utxo_index_marker = b"u".to_vec();
+    if store.get(Family::Default, &utxo_index_marker).is_some() {
+        warn!("deleting the UTXO index: re-index the DB from scratch for using --index-utxos");
+    }
+    for family in &[Family::Utxo, Family::Undo] {
+        let mut keys = vec![];
+        for row in store.scan(*family, b"") {
+            keys.push(row.key);
+            progress.inc();
+            if keys.len() >= 200_000 {
+                store.delete_and_write(mem::take(&mut keys), vec![]);
+            }
+        }
+        store.delete_and_write(keys, vec![]);
+    }
+    store.delete_and_write(vec![utxo_index_marker], vec![]);
+    info!("orphaned blocks indexed before the upgrade will be fetched from bitcoind on reorg");
+    Ok(())
+}
+
 fn full_compaction_marker() -> Row {
     Row {
         key: b"F".to_vec(),
@@ -189,6 +716,68 @@ pub fn full_compaction(store: DBStore) -> DBStore {
 }
 
 pub fn is_fully_compacted(store: &ReadStore) -> bool {
-    let marker = store.get(&full_compaction_marker().key);
+    let marker = store.get(Family::Default, &full_compaction_marker().key);
     marker.is_some()
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use super::*;
+
+    #[test]
+    fn test_schema_version() {
+        let path = env::temp_dir().join(format!("electrs-version-test-{}", process::id()));
+        let _ = fs::remove_dir_all(&path);
+        let open = || {
+            DBStore::open(
+                &path,
+                /*low_memory=*/ true,
+                /*cache_size=*/ 1 << 20,
+            )
+        };
+        let version = |store: &DBStore| store.get(Family::Default, &version_key());
+
+        // a new DB gets the current version
+        let store = open().unwrap();
+        assert_eq!(version(&store), Some(version_row(DB_VERSION).value));
+
+        // an unversioned (single keyspace) DB is migrated to the current version
+        let row = Row {
+            key: b"T0123".to_vec(),
+            value: b"height".to_vec(),
+        };
+        let utxo_key = b"U0123".to_vec();
+        let default_cf = store.cf(Family::Default);
+        store.db.delete_cf(default_cf, &version_key()).unwrap();
+        store.db.put_cf(default_cf, &row.key, &row.value).unwrap();
+        store.db.put_cf(default_cf, &utxo_key, b"").unwrap();
+        store.db.put_cf(default_cf, b"u", b"").unwrap();
+        drop(store);
+        let store = open().unwrap();
+        assert_eq!(version(&store), Some(version_row(DB_VERSION).value));
+        assert_eq!(store.get(Family::Tx, &row.key), Some(row.value));
+        assert_eq!(store.get(Family::Default, &row.key), None);
+        // the UTXO index is deleted (since it has to be re-indexed)
+        assert_eq!(store.get(Family::Utxo, &utxo_key), None);
+        assert_eq!(store.get(Family::Default, b"u"), None);
+
+        // a DB created by a newer version is refused
+        store.write(vec![version_row(DB_VERSION + 1)]);
+        drop(store);
+        assert!(open().is_err());
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_parse_db_stats() {
+        let text = "rocksdb.block.cache.miss COUNT : 12\n\
+                    rocksdb.block.cache.hit COUNT : 345\n\
+                    rocksdb.db.get.micros P50 : 1.5 P95 : 3.0 P99 : 4.0 P100 : 9.0 COUNT : 7 SUM : 20\n";
+        let stats = parse_db_stats(text);
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats["block.cache.miss"], 12);
+        assert_eq!(stats["block.cache.hit"], 345);
+    }
+}
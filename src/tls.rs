@@ -0,0 +1,86 @@
+use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::errors::*;
+
+// don't stat the certificate files on every accepted connection
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+fn modified(path: &Path) -> Result<SystemTime> {
+    fs::metadata(path)
+        .and_then(|m| m.modified())
+        .chain_err(|| format!("failed to stat {:?}", path))
+}
+
+fn build_acceptor(certfile: &Path, keyfile: &Path) -> Result<SslAcceptor> {
+    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())
+        .chain_err(|| "failed to create TLS acceptor")?;
+    builder
+        .set_private_key_file(keyfile, SslFiletype::PEM)
+        .chain_err(|| format!("failed to load TLS key from {:?}", keyfile))?;
+    builder
+        .set_certificate_chain_file(certfile)
+        .chain_err(|| format!("failed to load TLS certificate from {:?}", certfile))?;
+    builder
+        .check_private_key()
+        .chain_err(|| "TLS key does not match certificate")?;
+    Ok(builder.build())
+}
+
+struct Loaded {
+    mtimes: (SystemTime, SystemTime), // (certfile, keyfile)
+    checked: Instant,
+    acceptor: Arc<SslAcceptor>,
+}
+
+/// Provides TLS acceptors, reloading the certificate and key when their files change.
+pub struct TlsAcceptor {
+    certfile: PathBuf,
+    keyfile: PathBuf,
+    loaded: Mutex<Loaded>,
+}
+
+impl TlsAcceptor {
+    pub fn new(certfile: &Path, keyfile: &Path) -> Result<TlsAcceptor> {
+        let mtimes = (modified(certfile)?, modified(keyfile)?);
+        let acceptor = Arc::new(build_acceptor(certfile, keyfile)?);
+        Ok(TlsAcceptor {
+            certfile: certfile.to_path_buf(),
+            keyfile: keyfile.to_path_buf(),
+            loaded: Mutex::new(Loaded {
+                mtimes,
+                checked: Instant::now(),
+                acceptor,
+            }),
+        })
+    }
+
+    /// Returns the acceptor for a new session, reloading the certificate if its files have changed
+    /// (checked at most once per `RELOAD_CHECK_INTERVAL`).
+    pub fn current(&self) -> Arc<SslAcceptor> {
+        let mut loaded = self.loaded.lock().unwrap();
+        if loaded.checked.elapsed() < RELOAD_CHECK_INTERVAL {
+            return loaded.acceptor.clone();
+        }
+        loaded.checked = Instant::now();
+        let mtimes = match (modified(&self.certfile), modified(&self.keyfile)) {
+            (Ok(cert), Ok(key)) => (cert, key),
+            _ => return loaded.acceptor.clone(), // e.g. in the middle of replacing the files
+        };
+        if mtimes != loaded.mtimes {
+            match build_acceptor(&self.certfile, &self.keyfile) {
+                Ok(acceptor) => {
+                    info!("reloaded TLS certificate from {:?}", self.certfile);
+                    loaded.acceptor = Arc::new(acceptor);
+                    loaded.mtimes = mtimes;
+                }
+                // keep using the previous certificate until the new one is valid
+                Err(e) => warn!("failed to reload TLS certificate: {}", e),
+            }
+        }
+        loaded.acceptor.clone()
+    }
+}
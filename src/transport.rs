@@ -0,0 +1,750 @@
+// Non-blocking event loop, serving all Electrum RPC connections from a single thread.
+use error_chain::ChainedError;
+use mio::net::{TcpListener, TcpStream};
+use mio::{Events, Poll, PollOpt, Ready, Registration, SetReadiness, Token};
+use openssl::ssl::{HandshakeError, MidHandshakeSslStream, SslStream};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::sync::mpsc::{Receiver, Sender, TryRecvError};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::errors::*;
+use crate::tls::TlsAcceptor;
+use crate::util::Channel;
+use crate::websocket;
+
+const WAKER: Token = Token(0); // listeners use the following tokens, then the connections
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1); // for expiring handshakes
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+const MAX_PENDING_REQUESTS: usize = 16; // stop reading requests until some are answered
+const MAX_UNSENT_SIZE: usize = 1 << 20; // stop reading requests until the client reads the replies
+const MAX_BACKLOG_SIZE: usize = 64 << 20; // disconnect clients that don't read their notifications
+const MAX_LINE_SIZE: usize = 10 << 20; // large enough for any transaction broadcast
+const MAX_UPGRADE_SIZE: usize = 16 << 10;
+
+/// The listener a client connected through.
+#[derive(Clone)]
+pub enum Transport {
+    Tcp,
+    Tls(Arc<TlsAcceptor>),
+    WebSocket,
+}
+
+impl Transport {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Transport::Tcp => "tcp",
+            Transport::Tls(_) => "ssl",
+            Transport::WebSocket => "ws",
+        }
+    }
+}
+
+/// Connection events, reported by the event loop.
+#[derive(Debug)]
+pub enum Event {
+    Open(usize, SocketAddr, &'static str), // connection ID, peer address and transport label
+    Request(usize, String),
+    Close(usize),
+}
+
+/// Messages to be sent to a connection, as replies to its requests or as notifications.
+#[derive(Debug)]
+pub enum Output {
+    Reply(Vec<String>), // must be sent exactly once for each request
+    Notify(Vec<String>),
+    Close,
+}
+
+enum Command {
+    Output(usize, Output),
+    Exit,
+}
+
+/// Sends outputs to the event loop (from any thread).
+#[derive(Clone)]
+pub struct Handle {
+    tx: Sender<Command>,
+    readiness: SetReadiness,
+}
+
+impl Handle {
+    fn send(&self, cmd: Command) {
+        if self.tx.send(cmd).is_ok() {
+            let _ = self.readiness.set_readiness(Ready::readable());
+        } // otherwise, the event loop is already stopped
+    }
+
+    pub fn output(&self, id: usize, output: Output) {
+        self.send(Command::Output(id, output))
+    }
+
+    pub fn exit(&self) {
+        self.send(Command::Exit)
+    }
+}
+
+enum Stream {
+    Tcp(TcpStream),
+    Accepting(Option<MidHandshakeSslStream<TcpStream>>), // TLS handshake in progress
+    Tls(SslStream<TcpStream>),
+}
+
+impl Stream {
+    fn tls(
+        result: std::result::Result<SslStream<TcpStream>, HandshakeError<TcpStream>>,
+    ) -> Result<Stream> {
+        match result {
+            Ok(stream) => Ok(Stream::Tls(stream)),
+            Err(HandshakeError::WouldBlock(mid)) => Ok(Stream::Accepting(Some(mid))),
+            Err(e) => bail!("TLS handshake failed: {}", e),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            Stream::Accepting(_) => Err(io::ErrorKind::WouldBlock.into()),
+            Stream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            Stream::Accepting(_) => Err(io::ErrorKind::WouldBlock.into()),
+            Stream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            Stream::Accepting(_) => Ok(()),
+            Stream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+enum Framing {
+    Lines,     // a single JSON-RPC message per line
+    Upgrading, // waiting for the WebSocket opening handshake
+    WebSocket(websocket::Decoder),
+}
+
+struct Client {
+    stream: Stream,
+    socket: TcpStream, // for shutting down the connection, whatever the state of `stream`
+    addr: SocketAddr,
+    framing: Framing,
+    inbuf: Vec<u8>,
+    outbuf: Vec<u8>,
+    sent: usize, // bytes of `outbuf` that were already written
+    pending: usize,
+    deadline: Option<Instant>, // for completing the TLS and WebSocket handshakes
+    eof: bool,                 // no more requests will be received
+    closing: bool,             // closed by the server
+}
+
+impl Client {
+    fn new(socket: TcpStream, addr: SocketAddr, transport: &Transport) -> Result<Client> {
+        let handle = socket.try_clone().chain_err(|| "failed to clone socket")?;
+        let (stream, framing) = match transport {
+            Transport::Tcp => (Stream::Tcp(socket), Framing::Lines),
+            Transport::Tls(acceptor) => (
+                Stream::tls(acceptor.current().accept(socket))?,
+                Framing::Lines,
+            ),
+            Transport::WebSocket => (Stream::Tcp(socket), Framing::Upgrading),
+        };
+        let deadline = match (&stream, &framing) {
+            (Stream::Tcp(_), Framing::Lines) => None,
+            _ => Some(Instant::now() + HANDSHAKE_TIMEOUT),
+        };
+        Ok(Client {
+            stream,
+            socket: handle,
+            addr,
+            framing,
+            inbuf: vec![],
+            outbuf: vec![],
+            sent: 0,
+            pending: 0,
+            deadline,
+            eof: false,
+            closing: false,
+        })
+    }
+
+    fn expired(&self, now: Instant) -> bool {
+        match self.deadline {
+            Some(deadline) => deadline < now,
+            None => false,
+        }
+    }
+
+    fn unsent(&self) -> usize {
+        self.outbuf.len() - self.sent
+    }
+
+    fn done(&self) -> bool {
+        self.closing || (self.eof && self.pending == 0 && self.unsent() == 0)
+    }
+
+    fn queue(&mut self, messages: Vec<String>) {
+        for msg in messages {
+            match self.framing {
+                Framing::WebSocket(_) => self.outbuf.extend(websocket::text_frame(&msg)),
+                _ => {
+                    self.outbuf.extend(msg.into_bytes());
+                    self.outbuf.push(b'\n');
+                }
+            }
+        }
+    }
+
+    /// Handles an output or a readiness event, returning the received requests.
+    fn process(&mut self, output: Option<Output>) -> Result<Vec<String>> {
+        match output {
+            Some(Output::Reply(messages)) => {
+                self.pending -= 1;
+                self.queue(messages);
+            }
+            Some(Output::Notify(messages)) => {
+                self.queue(messages);
+                if self.unsent() > MAX_BACKLOG_SIZE {
+                    bail!("too many unsent notifications ({} bytes)", self.unsent());
+                }
+            }
+            Some(Output::Close) => {
+                self.closing = true;
+                return Ok(vec![]);
+            }
+            None => (),
+        }
+        self.handshake()?;
+        self.flush()?;
+        let requests = self.receive()?;
+        self.flush()?; // e.g. WebSocket handshake response and pongs
+        Ok(requests)
+    }
+
+    fn handshake(&mut self) -> Result<()> {
+        let mid = match self.stream {
+            Stream::Accepting(ref mut mid) => match mid.take() {
+                Some(mid) => mid,
+                None => bail!("TLS handshake already failed"),
+            },
+            _ => return Ok(()),
+        };
+        self.stream = Stream::tls(mid.handshake())?;
+        if let Stream::Tls(_) = self.stream {
+            self.deadline = None;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        while self.sent < self.outbuf.len() {
+            match self.stream.write(&self.outbuf[self.sent..]) {
+                Ok(0) => bail!("failed to send a reply: connection closed"),
+                Ok(n) => self.sent += n,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e).chain_err(|| "failed to send a reply"),
+            }
+        }
+        self.outbuf.clear();
+        self.sent = 0;
+        Ok(())
+    }
+
+    fn receive(&mut self) -> Result<Vec<String>> {
+        let mut requests = vec![];
+        let mut chunk = [0u8; 64 << 10];
+        while !self.eof && self.pending < MAX_PENDING_REQUESTS && self.unsent() < MAX_UNSENT_SIZE {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => self.eof = true,
+                Ok(n) => self.parse(&chunk[..n], &mut requests)?,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e).chain_err(|| "failed to read a request"),
+            }
+        }
+        self.pending += requests.len();
+        Ok(requests)
+    }
+
+    fn parse(&mut self, data: &[u8], requests: &mut Vec<String>) -> Result<()> {
+        self.inbuf.extend_from_slice(data);
+        match self.framing {
+            Framing::Lines => {
+                if let Stream::Tcp(_) = self.stream {
+                    if self.inbuf.starts_with(&[22, 3, 1]) {
+                        // (very) naive SSL handshake detection
+                        bail!("invalid request - maybe SSL-encrypted data?")
+                    }
+                }
+                if data.contains(&b'\n') {
+                    while let Some(pos) = self.inbuf.iter().position(|b| *b == b'\n') {
+                        let line: Vec<u8> = self.inbuf.drain(..=pos).collect();
+                        requests.push(String::from_utf8(line).chain_err(|| "invalid UTF8")?);
+                    }
+                }
+                if self.inbuf.len() > MAX_LINE_SIZE {
+                    bail!("too large request: {} bytes", self.inbuf.len());
+                }
+            }
+            Framing::Upgrading => {
+                let end = self.inbuf.windows(4).position(|w| w == b"\r\n\r\n");
+                if let Some(pos) = end {
+                    let request: Vec<u8> = self.inbuf.drain(..pos + 4).collect();
+                    websocket::handshake(&mut &request[..], &mut self.outbuf)?;
+                    self.framing = Framing::WebSocket(websocket::Decoder::default());
+                    self.deadline = None;
+                    let rest = self.inbuf.split_off(0); // may already contain the first frames
+                    return self.parse(&rest, requests);
+                }
+                if self.inbuf.len() > MAX_UPGRADE_SIZE {
+                    bail!("too large upgrade request: {} bytes", self.inbuf.len());
+                }
+            }
+            Framing::WebSocket(ref mut decoder) => {
+                decoder.feed(&self.inbuf);
+                self.inbuf.clear();
+                while let Some(msg) = decoder.next_message()? {
+                    match msg {
+                        websocket::Incoming::Text(req) => requests.push(req),
+                        websocket::Incoming::Ping(payload) => {
+                            self.outbuf.extend(websocket::pong_frame(&payload))
+                        }
+                        websocket::Incoming::Close => {
+                            self.eof = true;
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn shutdown(&mut self) {
+        if let Framing::WebSocket(_) = self.framing {
+            self.outbuf.extend(websocket::close_frame());
+        }
+        let _ = self.flush(); // best-effort
+        let _ = self.socket.shutdown(Shutdown::Both);
+    }
+}
+
+/// Accepts connections and handles their I/O, forwarding complete requests as events.
+pub struct EventLoop {
+    poll: Poll,
+    listeners: Vec<(TcpListener, Transport)>,
+    clients: HashMap<usize, Client>,
+    next_id: usize, // connection IDs are not reused, so stale outputs are dropped
+    commands: Receiver<Command>,
+    readiness: SetReadiness,
+    _registration: Registration,
+}
+
+impl EventLoop {
+    pub fn new(addrs: Vec<(SocketAddr, Transport)>) -> Result<(EventLoop, Handle)> {
+        let poll = Poll::new().chain_err(|| "failed to create poll")?;
+        let (registration, readiness) = Registration::new2();
+        poll.register(&registration, WAKER, Ready::readable(), PollOpt::edge())
+            .chain_err(|| "failed to register waker")?;
+        let mut listeners = vec![];
+        for (addr, transport) in addrs {
+            let listener =
+                TcpListener::bind(&addr).chain_err(|| format!("bind({}) failed", addr))?;
+            let token = Token(WAKER.0 + 1 + listeners.len());
+            poll.register(&listener, token, Ready::readable(), PollOpt::edge())
+                .chain_err(|| format!("failed to register {}", addr))?;
+            match transport {
+                Transport::Tcp => info!("RPC server running on {}", addr),
+                Transport::Tls(_) => info!("TLS RPC server running on {}", addr),
+                Transport::WebSocket => info!("WebSocket RPC server running on {}", addr),
+            }
+            listeners.push((listener, transport));
+        }
+        let commands = Channel::new();
+        let handle = Handle {
+            tx: commands.sender(),
+            readiness: readiness.clone(),
+        };
+        let next_id = WAKER.0 + 1 + listeners.len();
+        Ok((
+            EventLoop {
+                poll,
+                listeners,
+                clients: HashMap::new(),
+                next_id,
+                commands: commands.into_receiver(),
+                readiness,
+                _registration: registration,
+            },
+            handle,
+        ))
+    }
+
+    /// Runs until `Handle::exit()` is called, reporting connection events to `handler`.
+    pub fn run<F: FnMut(Event)>(mut self, mut handler: F) {
+        let mut events = Events::with_capacity(1024);
+        loop {
+            if let Err(e) = self.poll.poll(&mut events, Some(POLL_INTERVAL)) {
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                panic!("poll failed: {}", e);
+            }
+            for event in events.iter() {
+                match event.token() {
+                    WAKER => {
+                        if !self.handle_commands(&mut handler) {
+                            trace!("closing {} RPC connections", self.clients.len());
+                            for client in self.clients.values_mut() {
+                                client.shutdown();
+                            }
+                            return;
+                        }
+                    }
+                    Token(i) if i <= self.listeners.len() => self.accept(i - 1, &mut handler),
+                    Token(id) => self.process(id, None, &mut handler),
+                }
+            }
+            self.expire(&mut handler);
+        }
+    }
+
+    fn handle_commands<F: FnMut(Event)>(&mut self, handler: &mut F) -> bool {
+        let _ = self.readiness.set_readiness(Ready::empty());
+        loop {
+            match self.commands.try_recv() {
+                Ok(Command::Output(id, output)) => self.process(id, Some(output), handler),
+                Ok(Command::Exit) | Err(TryRecvError::Disconnected) => return false,
+                Err(TryRecvError::Empty) => return true,
+            }
+        }
+    }
+
+    fn accept<F: FnMut(Event)>(&mut self, index: usize, handler: &mut F) {
+        loop {
+            let (listener, transport) = &self.listeners[index];
+            let (socket, addr) = match listener.accept() {
+                Ok(accepted) => accepted,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    warn!("accept failed: {}", e);
+                    return;
+                }
+            };
+            let id = self.next_id;
+            self.next_id += 1;
+            let opts = PollOpt::edge();
+            if let Err(e) = self.poll.register(
+                &socket,
+                Token(id),
+                Ready::readable() | Ready::writable(),
+                opts,
+            ) {
+                warn!("[{}] failed to register connection: {}", addr, e);
+                continue;
+            }
+            let label = transport.label();
+            match Client::new(socket, addr, transport) {
+                Ok(client) => {
+                    self.clients.insert(id, client);
+                    handler(Event::Open(id, addr, label));
+                }
+                Err(e) => warn!("[{}] failed to open connection: {}", addr, e),
+            }
+        }
+    }
+
+    fn process<F: FnMut(Event)>(&mut self, id: usize, output: Option<Output>, handler: &mut F) {
+        let client = match self.clients.get_mut(&id) {
+            Some(client) => client,
+            None => return, // already closed
+        };
+        match client.process(output) {
+            Ok(requests) => {
+                for request in requests {
+                    handler(Event::Request(id, request));
+                }
+                if client.done() {
+                    self.close(id, handler);
+                }
+            }
+            Err(e) => {
+                warn!("[{}] connection failed: {}", client.addr, e.display_chain());
+                self.close(id, handler);
+            }
+        }
+    }
+
+    fn expire<F: FnMut(Event)>(&mut self, handler: &mut F) {
+        let now = Instant::now();
+        let expired: Vec<usize> = self
+            .clients
+            .iter()
+            .filter(|(_, client)| client.expired(now))
+            .map(|(id, _)| *id)
+            .collect();
+        for id in expired {
+            warn!("[{}] handshake timed out", self.clients[&id].addr);
+            self.close(id, handler);
+        }
+    }
+
+    fn close<F: FnMut(Event)>(&mut self, id: usize, handler: &mut F) {
+        if let Some(mut client) = self.clients.remove(&id) {
+            client.shutdown();
+            handler(Event::Close(id));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::asn1::Asn1Time;
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+    use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+    use openssl::x509::{X509NameBuilder, X509};
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+    use std::sync::Arc;
+    use std::thread::JoinHandle;
+    use std::time::Duration;
+    use std::{env, fs, process};
+
+    use super::{Event, EventLoop, Handle, Output, Transport, MAX_PENDING_REQUESTS};
+    use crate::tls::TlsAcceptor;
+    use crate::util::spawn_thread;
+    use crate::websocket;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    struct Server {
+        addrs: Vec<SocketAddr>, // by transport
+        handle: Handle,
+        events: Receiver<Event>,
+        thread: JoinHandle<()>,
+    }
+
+    impl Server {
+        fn start(transports: Vec<Transport>) -> Server {
+            let listeners = transports
+                .into_iter()
+                .map(|transport| ("127.0.0.1:0".parse().unwrap(), transport))
+                .collect();
+            let (event_loop, handle) = EventLoop::new(listeners).unwrap();
+            let addrs = event_loop
+                .listeners
+                .iter()
+                .map(|(listener, _)| listener.local_addr().unwrap())
+                .collect();
+            let (tx, events) = mpsc::channel();
+            let thread = spawn_thread("event_loop", move || {
+                event_loop.run(|event| {
+                    let _ = tx.send(event);
+                })
+            });
+            Server {
+                addrs,
+                handle,
+                events,
+                thread,
+            }
+        }
+
+        fn connect(&self, index: usize) -> (TcpStream, usize) {
+            let stream = TcpStream::connect(self.addrs[index]).unwrap();
+            stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+            match self.events.recv_timeout(TIMEOUT).unwrap() {
+                Event::Open(id, addr, _) => {
+                    assert_eq!(addr, stream.local_addr().unwrap());
+                    (stream, id)
+                }
+                event => panic!("unexpected event: {:?}", event),
+            }
+        }
+
+        fn request(&self, id: usize) -> String {
+            match self.events.recv_timeout(TIMEOUT).unwrap() {
+                Event::Request(i, request) if i == id => request,
+                event => panic!("unexpected event: {:?}", event),
+            }
+        }
+
+        fn closed(&self, id: usize) {
+            match self.events.recv_timeout(TIMEOUT).unwrap() {
+                Event::Close(i) if i == id => (),
+                event => panic!("unexpected event: {:?}", event),
+            }
+        }
+
+        fn idle(&self) {
+            match self.events.recv_timeout(Duration::from_millis(200)) {
+                Err(RecvTimeoutError::Timeout) => (),
+                result => panic!("unexpected event: {:?}", result),
+            }
+        }
+
+        fn reply(&self, id: usize, reply: &str) {
+            self.handle
+                .output(id, Output::Reply(vec![reply.to_owned()]));
+        }
+
+        fn stop(self) {
+            self.handle.exit();
+            self.thread.join().expect("event loop panicked");
+        }
+    }
+
+    fn read_exact(stream: &mut impl Read, len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        stream.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    fn tls_acceptor() -> Arc<TlsAcceptor> {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "localhost").unwrap();
+        let name = name.build();
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(&name).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+
+        let dir = env::temp_dir();
+        let certfile = dir.join(format!("electrs-tls-test-{}.crt", process::id()));
+        let keyfile = dir.join(format!("electrs-tls-test-{}.key", process::id()));
+        fs::write(&certfile, cert.build().to_pem().unwrap()).unwrap();
+        fs::write(&keyfile, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        let acceptor = TlsAcceptor::new(&certfile, &keyfile).unwrap();
+        let _ = fs::remove_file(&certfile);
+        let _ = fs::remove_file(&keyfile);
+        Arc::new(acceptor)
+    }
+
+    #[test]
+    fn test_lines() {
+        let server = Server::start(vec![Transport::Tcp]);
+        let (mut stream, id) = server.connect(0);
+        stream
+            .write_all(b"{\"id\": 1}\n{\"id\": 2}\n{\"id\"")
+            .unwrap();
+        assert_eq!(server.request(id), "{\"id\": 1}\n");
+        assert_eq!(server.request(id), "{\"id\": 2}\n");
+        server.idle(); // the last request is incomplete
+        stream.write_all(b": 3}\n").unwrap();
+        assert_eq!(server.request(id), "{\"id\": 3}\n");
+
+        server.reply(id, "1");
+        server
+            .handle
+            .output(id, Output::Notify(vec!["n".to_owned()]));
+        server.reply(id, "2");
+        assert_eq!(read_exact(&mut stream, 6), b"1\nn\n2\n");
+
+        stream.write_all(b"\x16\x03\x01").unwrap(); // SSL handshake on a TCP port
+        server.closed(id);
+        server.stop();
+    }
+
+    #[test]
+    fn test_websocket() {
+        let server = Server::start(vec![Transport::WebSocket]);
+        let (mut stream, id) = server.connect(0);
+        let request = b"{\"id\": 1}";
+        let mut data = b"GET / HTTP/1.1\r\n\
+            Host: localhost\r\n\
+            Upgrade: websocket\r\n\
+            Connection: Upgrade\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+            Sec-WebSocket-Version: 13\r\n\r\n"
+            .to_vec();
+        data.extend_from_slice(&[0x81, 0x80 | request.len() as u8, 1, 2, 3, 4]); // masked text frame
+        data.extend(
+            request
+                .iter()
+                .enumerate()
+                .map(|(i, b)| b ^ (i % 4 + 1) as u8),
+        );
+        stream.write_all(&data).unwrap();
+        assert_eq!(server.request(id).as_bytes(), &request[..]);
+
+        let response = b"HTTP/1.1 101 Switching Protocols\r\n\
+            Upgrade: websocket\r\n\
+            Connection: Upgrade\r\n\
+            Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n";
+        assert_eq!(read_exact(&mut stream, response.len()), &response[..]);
+        server.reply(id, "{\"id\": 1, \"result\": null}");
+        let frame = websocket::text_frame("{\"id\": 1, \"result\": null}");
+        assert_eq!(read_exact(&mut stream, frame.len()), frame);
+
+        stream.write_all(&[0x88, 0x80, 0, 0, 0, 0]).unwrap(); // close frame
+        server.closed(id);
+        server.stop();
+    }
+
+    #[test]
+    fn test_backpressure() {
+        let server = Server::start(vec![Transport::Tcp]);
+        let (mut stream, id) = server.connect(0);
+        let requests = "{}\n".repeat(MAX_PENDING_REQUESTS);
+        stream.write_all(requests.as_bytes()).unwrap();
+        for _ in 0..MAX_PENDING_REQUESTS {
+            server.request(id);
+        }
+        stream.write_all(b"{}\n{}\n").unwrap();
+        server.idle(); // too many pending requests
+        server.reply(id, "{}");
+        server.request(id);
+        server.request(id);
+        assert_eq!(read_exact(&mut stream, 3), b"{}\n");
+        server.stop();
+    }
+
+    #[test]
+    fn test_tls() {
+        let server = Server::start(vec![Transport::Tls(tls_acceptor())]);
+
+        let (mut stream, id) = server.connect(0);
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap(); // not a TLS handshake
+        server.closed(id);
+        let (stream, id) = server.connect(0);
+        drop(stream); // aborted during the handshake
+        server.closed(id);
+
+        let (stream, id) = server.connect(0);
+        let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+        connector.set_verify(SslVerifyMode::NONE);
+        let mut stream = connector.build().connect("localhost", stream).unwrap();
+        stream.write_all(b"{\"id\": 1}\n").unwrap();
+        assert_eq!(server.request(id), "{\"id\": 1}\n");
+        server.reply(id, "{}");
+        assert_eq!(read_exact(&mut stream, 3), b"{}\n");
+        server.stop();
+    }
+}
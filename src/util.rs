@@ -62,6 +62,7 @@ impl fmt::Debug for HeaderEntry {
     }
 }
 
+#[derive(Clone)]
 pub struct HeaderList {
     headers: Vec<HeaderEntry>,
     heights: HashMap<Sha256dHash, usize>,
@@ -154,6 +155,12 @@ impl HeaderList {
         }
     }
 
+    /// Keeps only the first `height` headers (e.g. after disconnecting the rest).
+    pub fn truncate(&mut self, height: usize) {
+        self.headers.truncate(height);
+        self.tip = self.headers.last().map(|h| *h.hash()).unwrap_or_default();
+    }
+
     pub fn header_by_blockhash(&self, blockhash: &Sha256dHash) -> Option<&HeaderEntry> {
         let height = self.heights.get(blockhash)?;
         let header = self.headers.get(*height)?;
@@ -0,0 +1,270 @@
+// Minimal WebSocket (RFC 6455) server support, for carrying Electrum JSON-RPC messages.
+use base64;
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+
+use crate::errors::*;
+
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const MAX_MESSAGE_SIZE: usize = 10 << 20; // large enough for any transaction broadcast
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+fn accept_key(key: &str) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.input_str(key);
+    sha1.input_str(ACCEPT_GUID);
+    let mut digest = [0u8; 20];
+    sha1.result(&mut digest);
+    base64::encode(&digest)
+}
+
+/// Reads the client's HTTP upgrade request and completes the opening handshake.
+pub fn handshake(reader: &mut dyn BufRead, writer: &mut dyn Write) -> Result<()> {
+    let mut request_line = String::new();
+    reader
+        .read_line(&mut request_line)
+        .chain_err(|| "failed to read upgrade request")?;
+    if !request_line.starts_with("GET ") {
+        bail!("invalid upgrade request: {:?}", request_line.trim_end());
+    }
+    let mut headers = HashMap::new();
+    loop {
+        let mut line = String::new();
+        if reader
+            .read_line(&mut line)
+            .chain_err(|| "failed to read upgrade request")?
+            == 0
+        {
+            bail!("disconnected during handshake");
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let parts: Vec<&str> = line.splitn(2, ':').collect();
+        if parts.len() == 2 {
+            headers.insert(parts[0].trim().to_lowercase(), parts[1].trim().to_owned());
+        }
+    }
+    let upgrade = headers.get("upgrade").map(String::as_str).unwrap_or("");
+    if !upgrade.eq_ignore_ascii_case("websocket") {
+        bail!("not a WebSocket upgrade request: {:?}", headers);
+    }
+    let key = headers
+        .get("sec-websocket-key")
+        .chain_err(|| "missing Sec-WebSocket-Key")?;
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(key)
+    );
+    writer
+        .write_all(response.as_bytes())
+        .chain_err(|| "failed to send handshake response")
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/// Parses a single (masked) client frame from the beginning of `buf`.
+/// Returns the frame and its encoded length, or `None` if more data is needed.
+fn parse_frame(buf: &[u8]) -> Result<Option<(Frame, usize)>> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+    let fin = buf[0] & 0x80 != 0;
+    let opcode = buf[0] & 0x0F;
+    if buf[1] & 0x80 == 0 {
+        bail!("unmasked client frame");
+    }
+    let (len, mut pos) = match buf[1] & 0x7F {
+        126 => {
+            if buf.len() < 4 {
+                return Ok(None);
+            }
+            (u64::from(buf[2]) << 8 | u64::from(buf[3]), 4)
+        }
+        127 => {
+            if buf.len() < 10 {
+                return Ok(None);
+            }
+            let len = buf[2..10]
+                .iter()
+                .fold(0u64, |len, byte| len << 8 | u64::from(*byte));
+            (len, 10)
+        }
+        len => (u64::from(len), 2),
+    };
+    if len > MAX_MESSAGE_SIZE as u64 {
+        bail!("too large frame: {} bytes", len);
+    }
+    let len = len as usize;
+    if buf.len() < pos + 4 + len {
+        return Ok(None);
+    }
+    let mask = [buf[pos], buf[pos + 1], buf[pos + 2], buf[pos + 3]];
+    pos += 4;
+    let payload = buf[pos..pos + len]
+        .iter()
+        .enumerate()
+        .map(|(i, byte)| byte ^ mask[i % 4])
+        .collect();
+    Ok(Some((
+        Frame {
+            fin,
+            opcode,
+            payload,
+        },
+        pos + len,
+    )))
+}
+
+/// Encodes a single (unmasked, unfragmented) server frame.
+fn encode_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x80 | opcode];
+    let len = payload.len();
+    if len < 126 {
+        frame.push(len as u8);
+    } else if len <= 0xFFFF {
+        frame.push(126);
+        frame.extend_from_slice(&[(len >> 8) as u8, len as u8]);
+    } else {
+        frame.push(127);
+        frame.extend((0..8).rev().map(|i| (len as u64 >> (8 * i)) as u8));
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+pub fn text_frame(text: &str) -> Vec<u8> {
+    encode_frame(OPCODE_TEXT, text.as_bytes())
+}
+
+pub fn pong_frame(payload: &[u8]) -> Vec<u8> {
+    encode_frame(OPCODE_PONG, payload)
+}
+
+pub fn close_frame() -> Vec<u8> {
+    encode_frame(OPCODE_CLOSE, &[])
+}
+
+#[derive(Debug)]
+pub enum Incoming {
+    Text(String),
+    Ping(Vec<u8>),
+    Close,
+}
+
+/// Reassembles (possibly fragmented) client messages from a stream of frames.
+#[derive(Default)]
+pub struct Decoder {
+    buf: Vec<u8>,
+    fragments: Vec<u8>,
+}
+
+impl Decoder {
+    /// Appends data received from the client.
+    pub fn feed(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Returns the next complete message, or `None` if more data is needed.
+    pub fn next_message(&mut self) -> Result<Option<Incoming>> {
+        loop {
+            let (frame, len) = match parse_frame(&self.buf)? {
+                Some(parsed) => parsed,
+                None => return Ok(None),
+            };
+            self.buf.drain(..len);
+            match frame.opcode {
+                OPCODE_TEXT | OPCODE_BINARY | OPCODE_CONTINUATION => {
+                    self.fragments.extend(frame.payload);
+                    if self.fragments.len() > MAX_MESSAGE_SIZE {
+                        bail!("too large message: {} bytes", self.fragments.len());
+                    }
+                    if frame.fin {
+                        let data = self.fragments.split_off(0);
+                        let text = String::from_utf8(data).chain_err(|| "invalid UTF8")?;
+                        return Ok(Some(Incoming::Text(text)));
+                    }
+                }
+                OPCODE_PING => return Ok(Some(Incoming::Ping(frame.payload))),
+                OPCODE_PONG => continue,
+                OPCODE_CLOSE => return Ok(Some(Incoming::Close)),
+                opcode => bail!("unsupported opcode {:#x}", opcode),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let mut frame = encode_frame(opcode, payload);
+        if !fin {
+            frame[0] &= 0x7F;
+        }
+        let header_len = frame.len() - payload.len();
+        frame[1] |= 0x80;
+        let masked = payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]);
+        frame.truncate(header_len);
+        frame.extend_from_slice(&mask);
+        frame.extend(masked);
+        frame
+    }
+
+    #[test]
+    fn test_accept_key() {
+        // https://tools.ietf.org/html/rfc6455#section-1.3
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn test_decode_messages() {
+        let request = r#"{"id": 1, "method": "server.ping", "params": []}"#.repeat(10);
+        let mut data = client_frame(false, OPCODE_TEXT, &request.as_bytes()[..100]);
+        data.extend(client_frame(true, OPCODE_PING, b"ping"));
+        data.extend(client_frame(
+            true,
+            OPCODE_CONTINUATION,
+            &request.as_bytes()[100..],
+        ));
+        data.extend(client_frame(true, OPCODE_CLOSE, b""));
+
+        let mut decoder = Decoder::default();
+        let mut messages = vec![];
+        for byte in data {
+            decoder.feed(&[byte]); // make sure partial frames are handled
+            while let Some(msg) = decoder.next_message().unwrap() {
+                messages.push(msg);
+            }
+        }
+        assert_eq!(messages.len(), 3);
+        match (&messages[0], &messages[1], &messages[2]) {
+            (Incoming::Ping(payload), Incoming::Text(text), Incoming::Close) => {
+                assert_eq!(payload, b"ping");
+                assert_eq!(*text, request);
+            }
+            _ => panic!("unexpected messages: {:?}", messages),
+        }
+    }
+}